@@ -39,9 +39,18 @@ pub mod apdu {
         pub const PACKET_SIZE: usize = 64;
         /// Maximum size a full APDU (split across HID frames) can be
         pub const MAX_APDU_SIZE: usize = 255 + 5; // from nanos-secure-sdk/include/os.h IO_APDU_BUFFER_SIZE
+        /// Default time to wait for a reply to a single APDU, in seconds. This is
+        /// long because the user may need to confirm things on the device.
+        pub const DEFAULT_TIMEOUT_SECS: u64 = 120;
 
         pub const BTCHIP_CLA: u8 = 0xe0;
 
+        /// Oldest Bitcoin app version (major, minor, patch) we will talk to
+        pub const MIN_APP_VERSION: (u8, u8, u8) = (1, 1, 0);
+        /// First Bitcoin app major version whose primary protocol is not the
+        /// legacy one we speak
+        pub const NEW_PROTOCOL_MAJOR_VERSION: u8 = 2;
+
         /// Instructions
         pub mod ins {
             pub const SET_ALTERNATE_COIN_VERSION: u8 = 0x14;
@@ -64,6 +73,9 @@ pub mod apdu {
             pub const INS_NOT_SUPPORTED: u16 = 0x6D00;
             pub const DONGLE_LOCKED: u16 = 0x6982;
             pub const SIGN_REFUSED: u16 = 0x6985;
+            /// Not sent by the dongle itself: `serve` replies with this
+            /// when the dongle it relays to could not be reached
+            pub const TECHNICAL_PROBLEM: u16 = 0x6F00;
             pub mod exception {
                 pub const EXCEPTION: u16 = 0x6F01;
                 pub const INVALID_PARAMETER: u16 = 0x6F02;
@@ -73,13 +85,81 @@ pub mod apdu {
     }
 }
 
+/// Process exit codes. These are stable, so that scripts driving the
+/// application can branch on them.
+pub mod exit_code {
+    /// Success
+    pub const OK: i32 = 0;
+    /// Any error not covered below
+    pub const GENERAL: i32 = 1;
+    /// Bad command-line usage
+    pub const USAGE: i32 = 2;
+    /// No dongle, or more than one, was found
+    pub const DEVICE_MISSING: i32 = 3;
+    /// The dongle returned an error or could not be communicated with
+    pub const DEVICE_ERROR: i32 = 4;
+    /// The user refused an operation on the dongle
+    pub const DEVICE_REJECTED: i32 = 5;
+    /// The dongle is locked
+    pub const DEVICE_LOCKED: i32 = 6;
+    /// The wallet file could not be read or does not belong to this dongle
+    pub const WALLET_ERROR: i32 = 7;
+    /// The wallet does not have enough funds
+    pub const INSUFFICIENT_FUNDS: i32 = 8;
+    /// The operation would violate wallet policy, e.g. receiving twice to one address
+    pub const POLICY_VIOLATION: i32 = 9;
+    /// The user declined to continue at a prompt
+    pub const CANCELLED: i32 = 10;
+    /// A signature or attestation did not verify
+    pub const VERIFY_FAILED: i32 = 11;
+    /// A file we were asked to create already exists
+    pub const FILE_EXISTS: i32 = 12;
+    /// The dongle did not reply in time, or the operation deadline passed
+    pub const TIMEOUT: i32 = 13;
+}
+
 /// Wallet structure constants
 pub mod wallet {
     /// Magic bytes indicating a wallet file (bottom two are a version)
     /// First six bytes are guaranteed random: used `wget boards.4chan.org/b/ -O - | sha256sum` to compute
     pub const MAGIC: u64 = 0x3160_f90d_aae5_0001;
-    /// Magic bytes indicating a testnet wallet file
+    /// Magic bytes indicating a testnet wallet file. This and `MAGIC` also
+    /// identify the network of backups and of the logical wallet hash.
     pub const MAGIC_TESTNET: u64 = 0x3160_f90d_aae5_0002;
+    /// Magic bytes indicating a wallet file whose header records the version
+    /// of the software which wrote it and a set of `header_flags`. This is the
+    /// only magic written; `MAGIC` and `MAGIC_TESTNET` files are only read.
+    pub const MAGIC_FLAGS: u64 = 0x3160_f90d_aae5_0003;
+    /// Flags in the header of a `MAGIC_FLAGS` wallet file, saying which
+    /// optional fields it has
+    pub mod header_flags {
+        /// The wallet is for testnet
+        pub const TESTNET: u32 = 1;
+        /// The header records the master key fingerprint of the dongle
+        pub const FINGERPRINT: u32 = 2;
+        /// The header records a salt, and entry keys are layered with a passphrase
+        pub const PASSPHRASE: u32 = 4;
+        /// The file ends in a SHA256 checksum
+        pub const CHECKSUM: u32 = 8;
+        /// Every flag this version understands
+        pub const ALL: u32 = TESTNET | FINGERPRINT | PASSPHRASE | CHECKSUM;
+    }
+    /// Size, in bytes, of the SHA256 checksum ending a file with the checksum flag
+    pub const CHECKSUM_SIZE: usize = 32;
+    /// Magic bytes indicating a passphrase-encrypted backup file (bottom two are a version)
+    pub const BACKUP_MAGIC: u64 = 0x3160_f90d_aae5_1001;
+    /// scrypt log2(N) parameter used to derive backup and wallet keys from a passphrase
+    pub const BACKUP_SCRYPT_LOG_N: u8 = 15;
+    /// scrypt r parameter used to derive backup keys from a passphrase
+    pub const BACKUP_SCRYPT_R: u32 = 8;
+    /// scrypt p parameter used to derive backup keys from a passphrase
+    pub const BACKUP_SCRYPT_P: u32 = 1;
+    /// Maximum number of entries a wallet can have; entries use hardened BIP32
+    /// indices, so there are only 2^31 of them
+    pub const MAX_ENTRIES: usize = 0x8000_0000;
+    /// Number of previous versions of a wallet file to keep beside it, as
+    /// `<filename>.1` (most recent) through `<filename>.N`
+    pub const WALLET_BACKUPS_KEPT: usize = 3;
     /// Size, in bytes, of the data block for each entry.
     pub const DECRYPTED_ENTRY_SIZE: usize = 336;
     /// Size, in bytes, of the AES-CTR-encrypted data block.
@@ -91,6 +171,25 @@ pub mod wallet {
     /// An amount of satoshis which, if we have change worth less than, we simply
     /// drop it into fees
     pub const CHANGE_DUST: u64 = 1_0000; // 0.0001 BTC, around 10c USD
+    /// Maximum number of bytes of data in an OP_RETURN output which is still
+    /// standard for relay
+    pub const MAX_DATA_BYTES: usize = 80;
+    /// Estimated size in bytes a signed input adds to a transaction
+    pub const INPUT_SIZE_ESTIMATE: u64 = 150; // 40 txin stuff, 72 sig, 33 key
+    /// Maximum number of inputs to put into one consolidation transaction, to
+    /// keep each signing session on the dongle a reasonable length
+    pub const CONSOLIDATE_MAX_INPUTS: usize = 50;
+    /// Smallest value in satoshis of an output, other than OP_RETURN, which
+    /// nodes will relay
+    pub const DUST_LIMIT: u64 = 546;
+    /// Receives smaller than this many satoshis are flagged for review unless
+    /// the user overrides it
+    pub const RECEIVE_DUST: u64 = 546;
+    /// Number of entries to encrypt between saves when extending a wallet
+    pub const EXTEND_CHUNK_SIZE: usize = 25;
+    /// When handing out an address leaves fewer than this many entries after
+    /// it, suggest that the user extend the wallet
+    pub const LOW_ENTRIES_WARNING: usize = 10;
 }
 
 
@@ -22,13 +22,13 @@ use hex::ToHex;
 use hid;
 use log::LogLevel;
 use std::cmp;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use constants;
 use dongle::message::Command;
 use error::Error;
 
-use super::{Dongle, Product};
+use super::{reply_timeout, Dongle, Product};
 
 /// Structure representing the device
 pub struct HardDongle {
@@ -39,6 +39,19 @@ pub struct HardDongle {
     /// it to drop before the manager is deallocated
     handle: Option<hid::Handle>,
     product: Product,
+    /// How long to wait for each reply
+    timeout: Duration,
+    /// Time after which no more APDUs will be sent
+    deadline: Option<Instant>,
+}
+
+impl HardDongle {
+    /// Sets how long to wait for each reply, and optionally a deadline after
+    /// which no further APDUs will be sent
+    pub fn set_timeouts(&mut self, timeout: Duration, deadline: Option<Instant>) {
+        self.timeout = timeout;
+        self.deadline = deadline;
+    }
 }
 
 impl Drop for HardDongle {
@@ -56,8 +69,9 @@ impl Dongle for HardDongle {
     fn exchange<C: Command>(&mut self, mut cmd: C) -> Result<(u16, Vec<u8>), Error> {
         let handle = self.handle.as_mut().unwrap();
         while let Some(msg) = cmd.encode_next(constants::apdu::ledger::MAX_APDU_SIZE) {
+            let timeout = reply_timeout(self.timeout, self.deadline)?;
             write_apdu(handle, &msg)?;
-            let reply = read_apdu(handle, Duration::from_secs(120))?;  // TODO make 2min configurable
+            let reply = read_apdu(handle, timeout)?;
             cmd.decode_reply(reply)?
         }
         Ok(cmd.into_reply())
@@ -89,7 +103,9 @@ pub fn get_unique() -> Result<HardDongle, Error> {
         1 => Ok(HardDongle {
             _hid_manager: hid,
             handle: found_dev, // guaranteed to be Some(handle)
-            product: Product::NanoS
+            product: Product::NanoS,
+            timeout: Duration::from_secs(constants::apdu::ledger::DEFAULT_TIMEOUT_SECS),
+            deadline: None,
         }),
         _ => Err(Error::DongleNotUnique)
     }
@@ -153,7 +169,7 @@ fn read_apdu(handle: &mut hid::Handle, timeout: Duration) -> Result<Vec<u8>, Err
         let mut data_frame = [0u8; constants::apdu::ledger::PACKET_SIZE];
         let read_n = r.read(&mut data_frame[..], timeout)?;
         if read_n.is_none() {
-            return Err(Error::UnexpectedEof);
+            return Err(Error::Timeout);
         }
 
         // Sanity check the frame
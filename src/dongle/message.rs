@@ -160,6 +160,13 @@ impl Response for FirmwareVersion {
     }
 }
 
+impl FirmwareVersion {
+    /// Whether this version is at least the given one
+    pub fn at_least(&self, major: u8, minor: u8, patch: u8) -> bool {
+        (self.major_version, self.minor_version, self.patch_version) >= (major, minor, patch)
+    }
+}
+
 /// GET WALLET PUBLIC KEY  message
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetWalletPublicKey<'a> {
@@ -236,6 +243,9 @@ impl Response for WalletPublicKey {
     fn decode(data: &[u8]) -> Result<WalletPublicKey, Error> {
         let secp = Secp256k1::without_caps();
 
+        if data.is_empty() {
+            return Err(Error::UnexpectedEof);
+        }
         let pk_len = data[0] as usize;
         if 2 + pk_len > data.len() {
             return Err(Error::UnexpectedEof);
@@ -460,6 +470,46 @@ impl Command for GetRandom {
 }
 
 
+/// An already-encoded APDU, passed through unchanged. Used to relay APDUs
+/// from a remote icebox to a locally attached device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawApdu {
+    apdu: Option<Vec<u8>>,
+    reply: Vec<u8>,
+    sw: u16
+}
+
+impl RawApdu {
+    /// Constructor
+    pub fn new(apdu: Vec<u8>) -> RawApdu {
+        RawApdu {
+            apdu: Some(apdu),
+            reply: vec![],
+            sw: 0
+        }
+    }
+}
+
+impl Command for RawApdu {
+    fn encode_next(&mut self, _apdu_size: usize) -> Option<Vec<u8>> {
+        self.apdu.take()
+    }
+
+    fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
+        if data.len() < 2 {
+            return Err(Error::UnexpectedEof);
+        }
+        let sw2 = data.pop().unwrap();
+        let sw1 = data.pop().unwrap();
+        self.reply = data;
+        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
+        Ok(())
+    }
+
+    fn into_reply(self) -> (u16, Vec<u8>) {
+        (self.sw, self.reply)
+    }
+}
 /// GET RANDOM message
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetTrustedInput {
@@ -848,3 +898,29 @@ impl Command for SetAlternateCoinVersions {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{Command, FirmwareVersion, RawApdu, Response, WalletPublicKey};
+
+    #[test]
+    fn decode_short_replies() {
+        // Arbitrary short replies must be rejected rather than panic
+        for len in 0..80 {
+            let data = vec![0x41; len];
+            assert!(WalletPublicKey::decode(&data).is_err());
+        }
+        for len in 0..5 {
+            assert!(FirmwareVersion::decode(&vec![0; len]).is_err());
+        }
+    }
+
+    #[test]
+    fn raw_apdu_passthrough() {
+        let mut cmd = RawApdu::new(vec![0xe0, 0xc4, 0x00, 0x00, 0x00]);
+        assert_eq!(cmd.encode_next(260), Some(vec![0xe0, 0xc4, 0x00, 0x00, 0x00]));
+        assert_eq!(cmd.encode_next(260), None);
+        assert!(cmd.clone().decode_reply(vec![0x90]).is_err());
+        cmd.decode_reply(vec![0x01, 0x02, 0x90, 0x00]).unwrap();
+        assert_eq!(cmd.into_reply(), (0x9000, vec![0x01, 0x02]));
+    }
+}
@@ -19,6 +19,8 @@
 
 use bitcoin::{Transaction, SigHashType};
 use bitcoin::network::constants::Network;
+use std::cmp;
+use std::time::{Duration, Instant};
 
 use constants;
 use error::Error;
@@ -28,6 +30,7 @@ use util::convert_ledger_der_to_compact;
 
 pub mod ledger;
 pub mod message;
+pub mod speculos;
 
 /// Trait representing an abstroct hardware wallet
 pub trait Dongle {
@@ -49,6 +52,22 @@ pub trait Dongle {
         }
     }
 
+    /// Queries the device for its firmware version and checks that it is a
+    /// version of the Bitcoin app that we can talk to
+    fn check_app_version(&mut self) -> Result<message::FirmwareVersion, Error> {
+        let version = self.get_firmware_version()?;
+        let (major, minor, patch) = constants::apdu::ledger::MIN_APP_VERSION;
+        if !version.at_least(major, minor, patch) {
+            return Err(Error::AppTooOld(version.major_version, version.minor_version, version.patch_version));
+        }
+        if version.major_version >= constants::apdu::ledger::NEW_PROTOCOL_MAJOR_VERSION {
+            warn!("Bitcoin app version {}.{}.{} primarily uses a newer protocol than ICBOC speaks.",
+                  version.major_version, version.minor_version, version.patch_version);
+            warn!("If commands fail as unsupported, install a 1.x version of the app.");
+        }
+        Ok(version)
+    }
+
     /// Queries the device for a BIP32 extended pubkey
     fn get_public_key(&mut self, bip32_path: &[u32], display: bool) -> Result<message::WalletPublicKey, Error> {
         let command = message::GetWalletPublicKey::new(bip32_path, display);
@@ -157,6 +176,71 @@ pub enum Product {
     /// Used in unit tests
     TestJig,
     /// Ledger Nano S
-    NanoS
+    NanoS,
+    /// Speculos emulator reached over TCP
+    Speculos
+}
+
+/// A dongle reached over any of the transports we support
+pub enum AnyDongle {
+    /// A physical device attached over USB HID
+    Hid(ledger::HardDongle),
+    /// An emulator reached over TCP
+    Tcp(speculos::TcpDongle)
+}
+
+impl AnyDongle {
+    /// Sets how long to wait for each reply, and optionally a deadline after
+    /// which no further APDUs will be sent
+    pub fn set_timeouts(&mut self, timeout: Duration, deadline: Option<Instant>) {
+        match *self {
+            AnyDongle::Hid(ref mut d) => d.set_timeouts(timeout, deadline),
+            AnyDongle::Tcp(ref mut d) => d.set_timeouts(timeout, deadline)
+        }
+    }
+}
+
+impl Dongle for AnyDongle {
+    fn product(&self) -> Product {
+        match *self {
+            AnyDongle::Hid(ref d) => d.product(),
+            AnyDongle::Tcp(ref d) => d.product()
+        }
+    }
+
+    fn exchange<C: Command>(&mut self, cmd: C) -> Result<(u16, Vec<u8>), Error> {
+        match *self {
+            AnyDongle::Hid(ref mut d) => d.exchange(cmd),
+            AnyDongle::Tcp(ref mut d) => d.exchange(cmd)
+        }
+    }
+}
+
+/// Computes how long to wait for the next reply, given the per-APDU timeout
+/// and an optional overall deadline. Errors out if the deadline has passed.
+fn reply_timeout(timeout: Duration, deadline: Option<Instant>) -> Result<Duration, Error> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                Err(Error::Timeout)
+            } else {
+                Ok(cmp::min(timeout, deadline - now))
+            }
+        }
+        None => Ok(timeout)
+    }
+}
+
+/// Opens a dongle given a transport description: `hid` for the unique
+/// attached device, or `tcp:<host>:<port>` for an emulator
+pub fn open(transport: &str) -> Result<AnyDongle, Error> {
+    if transport == "hid" {
+        ledger::get_unique().map(AnyDongle::Hid)
+    } else if transport.starts_with("tcp:") {
+        speculos::connect(&transport[4..]).map(AnyDongle::Tcp)
+    } else {
+        Err(Error::Unsupported)
+    }
 }
 
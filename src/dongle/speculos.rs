@@ -0,0 +1,191 @@
+// ICBOC
+// Written in 2017 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Speculos Dongle
+//!
+//! Support for the Speculos Ledger emulator, which accepts APDUs over TCP.
+//! Each APDU is sent prefixed by its 4-byte big-endian length; each reply
+//! is a 4-byte big-endian length, that many bytes of data, then the 2-byte
+//! status word.
+//!
+//! `serve` speaks the same protocol from the other end, relaying APDUs to a
+//! locally attached dongle. This lets icebox run on a machine without USB
+//! access to the device, e.g. over an SSH tunnel to the machine which has it.
+//!
+
+use byteorder::{ByteOrder, BigEndian};
+use hex::ToHex;
+use log::LogLevel;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use constants;
+use dongle::message::{Command, RawApdu};
+use error::Error;
+
+use super::{reply_timeout, Dongle, Product};
+
+/// Structure representing a connection to the emulator
+pub struct TcpDongle {
+    stream: TcpStream,
+    /// How long to wait for each reply
+    timeout: Duration,
+    /// Time after which no more APDUs will be sent
+    deadline: Option<Instant>
+}
+
+impl TcpDongle {
+    /// Sets how long to wait for each reply, and optionally a deadline after
+    /// which no further APDUs will be sent
+    pub fn set_timeouts(&mut self, timeout: Duration, deadline: Option<Instant>) {
+        self.timeout = timeout;
+        self.deadline = deadline;
+    }
+}
+
+impl Dongle for TcpDongle {
+    fn product(&self) -> Product {
+        Product::Speculos
+    }
+
+    fn exchange<C: Command>(&mut self, mut cmd: C) -> Result<(u16, Vec<u8>), Error> {
+        while let Some(msg) = cmd.encode_next(constants::apdu::ledger::MAX_APDU_SIZE) {
+            let timeout = reply_timeout(self.timeout, self.deadline)?;
+            self.stream.set_read_timeout(Some(timeout))?;
+            write_apdu(&mut self.stream, &msg)?;
+            let reply = read_apdu(&mut self.stream)?;
+            cmd.decode_reply(reply)?
+        }
+        Ok(cmd.into_reply())
+    }
+}
+
+/// Connects to an emulator listening at `addr`, e.g. `127.0.0.1:9999`
+pub fn connect(addr: &str) -> Result<TcpDongle, Error> {
+    let stream = TcpStream::connect(addr)?;
+    Ok(TcpDongle {
+        stream: stream,
+        timeout: Duration::from_secs(constants::apdu::ledger::DEFAULT_TIMEOUT_SECS),
+        deadline: None
+    })
+}
+
+/// Listens at `addr` and relays each APDU received to `dongle`, sending back
+/// its reply, as though the dongle were an emulator. Connections are served
+/// one at a time, and there is no authentication, so `addr` must be a
+/// loopback address, reached through an SSH tunnel or similar, unless
+/// `allow_remote` is set.
+pub fn serve<D: Dongle>(dongle: &mut D, addr: &str, allow_remote: bool) -> Result<(), Error> {
+    if !allow_remote {
+        for sockaddr in addr.to_socket_addrs()? {
+            if !sockaddr.ip().is_loopback() {
+                return Err(Error::NonLoopbackAddress(addr.to_owned()));
+            }
+        }
+    }
+    let listener = TcpListener::bind(addr)?;
+    for stream in listener.incoming() {
+        // A client going away mid-exchange should not stop the server
+        match stream.map_err(Error::Io).and_then(|stream| serve_connection(dongle, stream)) {
+            Ok(()) => info!("Connection closed"),
+            Err(e) => warn!("Connection dropped: {}", e)
+        }
+    }
+    Ok(())
+}
+
+/// Relays APDUs from a single client until it disconnects
+fn serve_connection<D: Dongle>(dongle: &mut D, mut stream: TcpStream) -> Result<(), Error> {
+    info!("Accepted connection from {}", stream.peer_addr()?);
+    loop {
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(Error::Io(e))
+        }
+        let request_len = BigEndian::read_u32(&len) as usize;
+        if request_len > constants::apdu::ledger::MAX_APDU_SIZE {
+            warn!("Dropping connection after oversized APDU of {} bytes", request_len);
+            return Ok(());
+        }
+        let mut request = vec![0; request_len];
+        stream.read_exact(&mut request)?;
+
+        // Anything shorter than CLA INS P1 P2 Lc is not an APDU, and the
+        // dongle's framing assumes at least that much
+        let (sw, mut reply) = if request_len < 5 {
+            warn!("Rejecting APDU of only {} bytes", request_len);
+            (constants::apdu::ledger::sw::BAD_LENGTH, vec![])
+        } else {
+            match dongle.exchange(RawApdu::new(request)) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    warn!("Dongle failed to handle APDU: {}", e);
+                    (constants::apdu::ledger::sw::TECHNICAL_PROBLEM, vec![])
+                }
+            }
+        };
+        BigEndian::write_u32(&mut len, reply.len() as u32);
+        reply.push((sw >> 8) as u8);
+        reply.push(sw as u8);
+        stream.write_all(&len)?;
+        stream.write_all(&reply)?;
+        stream.flush()?;
+    }
+}
+
+/// Write a length-prefixed APDU to the emulator
+fn write_apdu(stream: &mut TcpStream, data: &[u8]) -> Result<(), Error> {
+    if log_enabled!(LogLevel::Debug) {
+        trace!("Sending message {}", data.to_hex());
+    }
+
+    let mut len = [0u8; 4];
+    BigEndian::write_u32(&mut len, data.len() as u32);
+    stream.write_all(&len)?;
+    stream.write_all(data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read a length-prefixed reply, including its status word, from the emulator
+fn read_apdu(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut len = [0u8; 4];
+    read_exact_or_timeout(stream, &mut len)?;
+    let receive_len = BigEndian::read_u32(&len) as usize;
+    if receive_len > constants::apdu::ledger::MAX_APDU_SIZE {
+        return Err(Error::ResponseWrongLength(0, receive_len));
+    }
+
+    // The length does not count the two-byte status word
+    let mut ret = vec![0; receive_len + 2];
+    read_exact_or_timeout(stream, &mut ret)?;
+
+    if log_enabled!(LogLevel::Debug) {
+        trace!("Got message {}", ret.to_hex());
+    }
+    Ok(ret)
+}
+
+/// Fills `buf` from the stream, reporting a read timeout as `Error::Timeout`
+fn read_exact_or_timeout(stream: &mut TcpStream, buf: &mut [u8]) -> Result<(), Error> {
+    match stream.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Err(Error::Timeout),
+        Err(e) => Err(Error::Io(e))
+    }
+}
+
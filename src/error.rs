@@ -16,6 +16,9 @@
 
 use std::{error, fmt, io, string};
 use bitcoin::network::serialize;
+use bitcoin::util::hash::Sha256dHash;
+use constants;
+use hex::ToHex;
 use hid;
 use secp256k1;
 
@@ -38,6 +41,8 @@ pub enum Error {
     DongleNotUnique,
     /// APDU reply had bad status word
     ApduBadStatus(u16),
+    /// The Bitcoin app on the device is older than we support (major, minor, patch)
+    AppTooOld(u8, u8, u8),
     /// APDU reply had wrong channel
     ApduWrongChannel,
     /// APDU reply had wrong tag
@@ -50,26 +55,100 @@ pub enum Error {
     InsufficientFunds(u64, u64),
     /// An wallet cannot produce anymore addresses
     WalletFull,
+    /// Attempted to use an account number which is not a valid hardened BIP32 index
+    AccountOutOfRange(u32),
+    /// Attempted to create a wallet with more entries than there are BIP32 indices (requested, max)
+    TooManyEntries(usize, usize),
     /// An encrypted wallet had a bad filesize
     WalletWrongSize(usize),
     /// An encrypted wallet had a bad magic (probably not a wallet)
     WalletWrongMagic(u64),
+    /// An encrypted wallet's header had flags this version does not understand
+    WalletUnknownFlags(u32),
+    /// A wallet is locked by another process; the lock file is given
+    WalletLocked(String),
+    /// A wallet file's checksum did not match its contents
+    WalletBadChecksum,
+    /// A wallet was last written by a newer major version of this software
+    WalletTooNew(u16, u8, u8),
+    /// Wallet has a passphrase but none was given
+    PassphraseRequired,
+    /// Wallet entries decrypted to garbage under the given passphrase
+    WrongPassphrase,
+    /// Wallet entries decrypted to garbage, so the wallet was made with another dongle
+    WrongDongle,
+    /// The dongle's master key fingerprint (second) is not the one recorded
+    /// in the wallet (first)
+    WrongDevice([u8; 4], [u8; 4]),
+    /// An entry decrypted to garbage though others did not, so the wallet is corrupted
+    EntryCorrupted(usize),
+    /// A backup file had a bad magic (probably not a backup)
+    BackupWrongMagic(u64),
+    /// A backup file's length did not match its header
+    BackupWrongSize(usize),
+    /// A backup file failed authentication (wrong passphrase or corrupted file)
+    BackupBadMac,
+    /// A payment queue failed authentication (wrong dongle or passphrase, or corrupted file)
+    QueueBadMac,
     /// Attempted to use a user ID that exceeds the field length of the wallet (used, max)
     UserIdTooLong(usize, usize),
     /// Attempted to use a note that exceeds the field length of the wallet (used, max)
     NoteTooLong(usize, usize),
     /// Tried to access entry not in the wallet
     EntryOutOfRange(usize),
+    /// An input requested for coin control is not an unspent wallet output
+    InputNotFound(Sha256dHash, u32),
     /// Searched for an address not in the wallet
     AddressNotFound,
     /// Attempted to receive twice to one address
     DoubleReceive,
     /// Received an unparseable signature
     BadSignature,
+    /// A spend template could not be parsed at the given line
+    BadTemplate(usize),
+    /// A spend template has no destinations
+    EmptyTemplate,
+    /// A percentage of this total amount does not fit in 64 bits
+    TemplateTotalTooLarge(u64),
+    /// A spend would have an output worth less than the dust limit
+    OutputBelowDust(u64),
     /// The dongle requested we do something unsupported
     Unsupported,
     /// Received APDU frame of shorter than expected length
-    UnexpectedEof
+    UnexpectedEof,
+    /// The dongle did not reply in time, or the operation deadline passed
+    Timeout,
+    /// Refused to serve the dongle at an address reachable from other machines
+    NonLoopbackAddress(String)
+}
+
+impl Error {
+    /// The process exit code which the application reports for this error
+    pub fn exit_code(&self) -> i32 {
+        use constants::apdu::ledger::sw;
+        use constants::exit_code;
+
+        match *self {
+            Error::Hid(_) | Error::DongleNotFound | Error::DongleNotUnique => exit_code::DEVICE_MISSING,
+            Error::ApduBadStatus(sw::SIGN_REFUSED) => exit_code::DEVICE_REJECTED,
+            Error::ApduBadStatus(sw::DONGLE_LOCKED) => exit_code::DEVICE_LOCKED,
+            Error::ApduBadStatus(_) | Error::ApduWrongChannel | Error::ApduWrongTag |
+            Error::ApduWrongSequence | Error::ResponseWrongLength(_, _) | Error::AppTooOld(_, _, _) |
+            Error::Unsupported | Error::UnexpectedEof => exit_code::DEVICE_ERROR,
+            Error::Io(_) | Error::WalletWrongSize(_) | Error::WalletWrongMagic(_) | Error::WalletBadChecksum |
+            Error::WalletUnknownFlags(_) | Error::WalletLocked(_) | Error::WalletTooNew(_, _, _) | Error::WrongDongle | Error::WrongDevice(_, _) |
+            Error::PassphraseRequired | Error::WrongPassphrase |
+            Error::EntryCorrupted(_) | Error::BackupWrongMagic(_) | Error::BackupWrongSize(_) |
+            Error::BackupBadMac | Error::QueueBadMac => exit_code::WALLET_ERROR,
+            Error::InsufficientFunds(_, _) | Error::InputNotFound(_, _) => exit_code::INSUFFICIENT_FUNDS,
+            Error::WalletFull | Error::DoubleReceive | Error::OutputBelowDust(_) => exit_code::POLICY_VIOLATION,
+            Error::BadSignature => exit_code::VERIFY_FAILED,
+            Error::BadTemplate(_) | Error::EmptyTemplate | Error::TemplateTotalTooLarge(_) |
+            Error::NonLoopbackAddress(_) => exit_code::USAGE,
+            Error::Timeout => exit_code::TIMEOUT,
+            _ => exit_code::GENERAL
+        }
+    }
 }
 
 impl From<serialize::Error> for Error {
@@ -124,22 +203,45 @@ impl error::Error for Error {
             Error::DongleNotFound => "Ledger device not found",
             Error::DongleNotUnique => "multiple Ledger devices found",
             Error::ApduBadStatus(_) => "bad APDU status word (is device unlocked?)",
+            Error::AppTooOld(_, _, _) => "Bitcoin app on device is too old",
             Error::ApduWrongChannel => "wrong APDU channel (is device running the right app?)",
             Error::ApduWrongTag => "wrong APDU tag (is device running the right app?)",
             Error::ApduWrongSequence => "bad APDU sequence no",
             Error::ResponseWrongLength(_,_) => "bad message length",
             Error::InsufficientFunds(_,_) => "insufficient funds",
             Error::WalletFull => "wallet is full, it has no more available addresses",
+            Error::AccountOutOfRange(_) => "account number out of range",
+            Error::TooManyEntries(_, _) => "too many entries",
             Error::WalletWrongSize(_) => "wallet had invalid length",
             Error::WalletWrongMagic(_) => "wallet had wrong magic",
+            Error::WalletUnknownFlags(_) => "wallet header had unknown flags",
+            Error::WalletTooNew(_, _, _) => "wallet was written by a newer version of this software",
+            Error::WalletLocked(_) => "wallet is in use by another icebox process",
+            Error::WalletBadChecksum => "wallet file checksum does not match (file corrupted or truncated? try recover)",
+            Error::WrongDongle => "wallet was not created with this dongle (or is corrupted)",
+            Error::WrongDevice(_, _) => "wrong device or wrong seed: dongle master fingerprint does not match wallet",
+            Error::PassphraseRequired => "wallet has a passphrase, but none was given (use --passphrase)",
+            Error::WrongPassphrase => "wallet did not decrypt with this passphrase (or was not created with this dongle)",
+            Error::EntryCorrupted(_) => "entry did not decrypt to valid data (wallet corrupted?)",
+            Error::BackupWrongMagic(_) => "backup had wrong magic",
+            Error::BackupWrongSize(_) => "backup had invalid length",
+            Error::BackupBadMac => "backup failed authentication (wrong passphrase?)",
+            Error::QueueBadMac => "payment queue failed authentication (wrong dongle or passphrase, or corrupted?)",
             Error::UserIdTooLong(_, _) => "user ID too long",
             Error::NoteTooLong(_, _) => "note too long",
             Error::EntryOutOfRange(_) => "tried to access entry outside of wallet",
+            Error::InputNotFound(_, _) => "requested input is not an unspent output of this wallet",
             Error::AddressNotFound => "address not found in wallet",
             Error::DoubleReceive => "attempted to receive twice to same address",
             Error::BadSignature => "unparseable signature",
+            Error::BadTemplate(_) => "could not parse spend template",
+            Error::EmptyTemplate => "spend template has no `pay` lines",
+            Error::TemplateTotalTooLarge(_) => "total amount too large to take percentages of",
+            Error::OutputBelowDust(_) => "output amount is below the dust limit",
             Error::Unsupported => "we were asked to do something unsupported",
-            Error::UnexpectedEof => "unexpected end of data"
+            Error::UnexpectedEof => "unexpected end of data",
+            Error::Timeout => "timed out waiting for dongle",
+            Error::NonLoopbackAddress(_) => "refusing to serve the dongle at a non-loopback address"
         }
     }
 }
@@ -153,13 +255,34 @@ impl fmt::Display for Error {
             Error::Secp(ref e) => fmt::Display::fmt(e, f),
             Error::Utf8(ref e) => fmt::Display::fmt(e, f),
             Error::ApduBadStatus(sw) => write!(f, "bad APDU status word {}", sw),
+            Error::AppTooOld(major, minor, patch) => {
+                let (min_major, min_minor, min_patch) = constants::apdu::ledger::MIN_APP_VERSION;
+                write!(f, "Bitcoin app version {}.{}.{} is too old, this needs Bitcoin app >= {}.{}.{}",
+                       major, minor, patch, min_major, min_minor, min_patch)
+            }
             Error::ResponseWrongLength(msg, len) => write!(f, "bad APDU response length {} for message 0x{:02x}", len, msg),
             Error::InsufficientFunds(had, required) => write!(f, "have {} but need {} satoshi to fund this transaction", had, required),
+            Error::AccountOutOfRange(account) => write!(f, "account {} is not less than 2^31", account),
+            Error::TooManyEntries(requested, max) => write!(f, "{} entries requested but a wallet can have at most {}", requested, max),
             Error::WalletWrongSize(len) => write!(f, "bad wallet size {}", len),
             Error::WalletWrongMagic(magic) => write!(f, "bad wallet magic {:08x}", magic),
+            Error::WalletUnknownFlags(flags) => write!(f, "wallet header has unknown flags {:08x}, was it written by a newer version?", flags),
+            Error::WalletLocked(ref path) => write!(f, "wallet is in use by another icebox process, which holds the lock on {}", path),
+            Error::WalletTooNew(major, minor, patch) => write!(f, "wallet was last written by version {}.{}.{}, refusing to overwrite it with older version {}",
+                                                               major, minor, patch, env!("CARGO_PKG_VERSION")),
+            Error::WrongDevice(wallet, dongle) => write!(f, "wrong device or wrong seed: wallet belongs to master fingerprint {}, but this dongle has {}",
+                                                         wallet.to_hex(), dongle.to_hex()),
+            Error::EntryCorrupted(entry) => write!(f, "entry {} did not decrypt to valid data (wallet corrupted?)", entry),
+            Error::BackupWrongMagic(magic) => write!(f, "bad backup magic {:08x}", magic),
+            Error::BackupWrongSize(len) => write!(f, "bad backup size {}", len),
             Error::UserIdTooLong(used, max) => write!(f, "user ID length {} exceeds max {}", used, max),
             Error::NoteTooLong(used, max) => write!(f, "user ID length {} exceeds max {}", used, max),
             Error::EntryOutOfRange(entry) => write!(f, "entry {} not in wallet", entry),
+            Error::InputNotFound(ref txid, vout) => write!(f, "{}:{} is not an unspent output of this wallet", txid, vout),
+            Error::BadTemplate(line) => write!(f, "could not parse line {} of spend template", line),
+            Error::TemplateTotalTooLarge(total) => write!(f, "total amount {} is too large to take percentages of", total),
+            Error::OutputBelowDust(amount) => write!(f, "output of {} satoshi is below the dust limit of {}, so would not be relayed", amount, constants::wallet::DUST_LIMIT),
+            Error::NonLoopbackAddress(ref addr) => write!(f, "{} is not a loopback address, and anyone who can reach it could use the dongle (use --allow-remote to serve it anyway)", addr),
             _ => f.write_str(error::Error::description(self))
         }
     }
@@ -31,6 +31,7 @@ extern crate base64;
 extern crate bitcoin;
 extern crate byteorder;
 extern crate crypto;
+extern crate fs2;
 #[macro_use] extern crate log;
 extern crate hex;
 extern crate hid;
@@ -40,7 +41,9 @@ extern crate time;
 pub mod constants;
 pub mod dongle;
 pub mod error;
+pub mod lock;
 pub mod spend;
+pub mod template;
 pub mod util;
 pub mod wallet;
 
@@ -0,0 +1,48 @@
+// ICBOC
+// Written in 2017 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Wallet Locking
+//!
+//! Advisory locks which stop two icebox processes from working on the same
+//! wallet at once, since whichever saved last would silently undo the
+//! other's changes. The lock for `wallet` is an OS file lock (`flock` or
+//! `LockFileEx`) on the file `wallet.lock`, so it is released by the OS
+//! however the process exits and needs no staleness checks. The file itself
+//! is left in place.
+//!
+
+use std::fs;
+
+use fs2::{self, FileExt};
+
+use error::Error;
+
+/// A held lock on a wallet file, released when dropped or when the process exits
+pub struct WalletLock {
+    _file: fs::File
+}
+
+impl WalletLock {
+    /// Takes the lock for the wallet at `filename`, failing if another
+    /// process holds it
+    pub fn acquire(filename: &str) -> Result<WalletLock, Error> {
+        let path = format!("{}.lock", filename);
+        let file = fs::OpenOptions::new().write(true).create(true).open(&path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(WalletLock { _file: file }),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => Err(Error::WalletLocked(path)),
+            Err(e) => Err(Error::Io(e))
+        }
+    }
+}
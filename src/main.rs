@@ -24,58 +24,502 @@
 extern crate bitcoin;
 extern crate hex;
 extern crate icebox;
+#[macro_use] extern crate log;
+extern crate rpassword;
 extern crate simplelog;
 
 use bitcoin::{Address, Transaction, TxOut};
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{self, Instruction};
 use bitcoin::network::constants::Network;
 use bitcoin::network::serialize::serialize_hex as bitcoin_serialize_hex;
 use bitcoin::network::serialize::deserialize as bitcoin_deserialize;
 use bitcoin::util::hash::Sha256dHash;
-use std::{env, io, fs, process};
-use std::io::{Write, BufRead};
+use hex::ToHex;
+use std::{cmp, env, fmt, io, fs, process};
+use std::io::{Read, Write, BufRead};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use icebox::dongle::Dongle;
+use icebox::dongle::{AnyDongle, Dongle};
 use icebox::error::Error;
-use icebox::constants::apdu::ledger::sw;
+use icebox::lock::WalletLock;
+use icebox::constants::apdu::ledger::{sw, DEFAULT_TIMEOUT_SECS};
+use icebox::constants::exit_code;
+use icebox::constants::wallet::{CONSOLIDATE_MAX_INPUTS, DUST_LIMIT, EXTEND_CHUNK_SIZE, LOW_ENTRIES_WARNING, MAX_DATA_BYTES, RECEIVE_DUST};
 use icebox::spend::Spend;
-use icebox::wallet::{EntryState, Update};
-use icebox::util::convert_compact_to_signmessage_rpc;
+use icebox::template::{SpendTemplate, TemplateAmount};
+use icebox::wallet::{bip32_path_string, commit_to_outputs, master_fingerprint, CoinSelection, EncryptedWallet, EntryState, Update, ENTRY_CSV_HEADER};
+use icebox::util::{convert_compact_to_signmessage_rpc, json_string, split_command_line, verify_signmessage};
 
-/// Prompt the user for some string data
+/// Prints text meant for the person running a command. In JSON mode this goes
+/// to stderr, so that stdout carries only the result.
+macro_rules! say {
+    ($json:expr, $($arg:tt)*) => (if $json { eprintln!($($arg)*) } else { println!($($arg)*) })
+}
+
+/// Prompt the user for some string data. The prompt goes to stderr so that it
+/// does not mix with JSON output on stdout.
 fn user_prompt(prompt: &str) -> String {
-    print!("{}: ", prompt);
-    io::stdout().flush().expect("flushing stdout");
+    eprint!("{}: ", prompt);
+    io::stderr().flush().expect("flushing stderr");
     let stdin = io::stdin();
     let lock = stdin.lock();
     let line_res = lock.lines().next().expect("getting next line from stdin");
     line_res.expect("reading from stdin")
 }
 
+/// Prompt the user for a passphrase on stderr, without echoing it
+fn passphrase_prompt(prompt: &str) -> String {
+    rpassword::prompt_password_stderr(&format!("{}: ", prompt)).expect("reading passphrase")
+}
+
+/// Steps walked through before signing when `--checklist` is given without
+/// a checklist file
+const DEFAULT_CHECKLIST: &'static [&'static str] = &[
+    "Verify every destination address with its recipient over a separate channel",
+    "Verify that the fee rate is appropriate for current network conditions",
+    "Be ready to verify the change address on the dongle screen when signing",
+];
+
+/// Removes a flag from the argument list, returning whether it was present
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+/// Removes a flag and its value from the argument list, returning the value
+fn take_option(args: &mut Vec<String>, flag: &str, name: &str) -> Result<Option<String>, Failed> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(pos) => {
+            if pos + 1 == args.len() {
+                return Err(usage(name));
+            }
+            let value = args.remove(pos + 1);
+            args.remove(pos);
+            Ok(Some(value))
+        }
+        None => Ok(None)
+    }
+}
+
+/// Removes a flag and its value from the argument list, returning the value
+/// parsed
+fn take_parsed<T: FromStr>(args: &mut Vec<String>, flag: &str, name: &str) -> Result<Option<T>, Failed>
+    where T::Err: fmt::Display
+{
+    match take_option(args, flag, name)? {
+        Some(value) => Ok(Some(parse_arg(&value, &format!("Parsing {} as number", flag))?)),
+        None => Ok(None)
+    }
+}
+
+/// Parses a command-line argument, reporting it if it does not parse
+fn parse_arg<T: FromStr>(arg: &str, msg: &str) -> Result<T, Failed>
+    where T::Err: fmt::Display
+{
+    check_parse(T::from_str(arg), msg)
+}
+
+/// Reports a failure to parse user input, as `pretty_unwrap` does for other
+/// errors, without halting
+fn check_parse<T, E: fmt::Display>(res: Result<T, E>, msg: &str) -> Result<T, Failed> {
+    res.map_err(|e| {
+        eprintln!("{}: {}", msg, e);
+        Failed(exit_code::USAGE)
+    })
+}
+
+/// Walks the user through a spend checklist, logging each confirmed step.
+/// Returns false if the user declined any step.
+fn run_checklist(steps: &[String], json: bool) -> bool {
+    say!(json, "Spend checklist: type YES to confirm each step.");
+    for (n, step) in steps.iter().enumerate() {
+        let answer = user_prompt(&format!("[{}/{}] {}", n + 1, steps.len(), step));
+        if answer != "YES" {
+            warn!("Checklist step {} declined: {}", n + 1, step);
+            return false;
+        }
+        info!("Checklist step {} confirmed: {}", n + 1, step);
+    }
+    let witness = user_prompt("Name of second person signing off on this spend");
+    let witness_note = user_prompt("Sign-off note");
+    info!("Checklist signed off by {}: {}", witness, witness_note);
+    true
+}
+
+/// Runs one of the `verify` commands, which need neither a wallet nor a
+/// dongle, and then halts the program
+fn verify_and_exit(args: &[String]) -> ! {
+    if args.len() < 6 {
+        usage_and_die(&args[0]);
+    }
+    let address: Address = exit_on_failure(parse_arg(&args[3], "Decoding address"));
+    let verified = match &args[2][..] {
+        "message" => {
+            pretty_unwrap("Verifying signature",
+                          verify_signmessage(&address, &args[4], args[5].as_bytes()))
+        }
+        "attestation" => {
+            let mut outputs = Vec::with_capacity(args.len() - 5);
+            for arg in &args[5..] {
+                let parts: Vec<&str> = arg.split(':').collect();
+                if parts.len() != 3 {
+                    eprintln!("Outputs must be given as <txid>:<vout>:<amount>, not {}", arg);
+                    process::exit(exit_code::USAGE);
+                }
+                let txid = exit_on_failure(check_parse(Sha256dHash::from_hex(parts[0]), "decoding txid hex"));
+                let vout = exit_on_failure(parse_arg(parts[1], "Parsing vout as number"));
+                let amount = exit_on_failure(parse_arg(parts[2], "Parsing amount as number"));
+                outputs.push((txid, vout, amount));
+            }
+            let commitment = commit_to_outputs(&mut outputs).to_hex();
+            println!("commitment: {}", commitment);
+            pretty_unwrap("Verifying signature",
+                          verify_signmessage(&address, &args[4], commitment.as_bytes()))
+        }
+        _ => usage_and_die(&args[0])
+    };
+    if verified {
+        println!("Signature is valid.");
+        process::exit(exit_code::OK);
+    } else {
+        println!("Signature is NOT valid for this address.");
+        process::exit(exit_code::VERIFY_FAILED);
+    }
+}
+
+/// Describes an output the way the dongle will show it, naming the address
+/// if it is one of `destinations`
+fn describe_output(output: &TxOut, destinations: &[Address]) -> String {
+    if let Some(addr) = destinations.iter().find(|addr| addr.script_pubkey() == output.script_pubkey) {
+        return format!("{} satoshi to {}", output.value, addr);
+    }
+    // Show the data an OP_RETURN output carries, without its push opcode
+    let mut instructions = output.script_pubkey.iter(false);
+    match (instructions.next(), instructions.next(), instructions.next()) {
+        (Some(Instruction::Op(opcodes::All::OP_RETURN)), Some(Instruction::PushBytes(data)), None) => {
+            format!("{} satoshi to OP_RETURN {}", output.value, data.to_hex())
+        }
+        _ => format!("{} satoshi to script {}", output.value, output.script_pubkey.clone().into_vec().to_hex())
+    }
+}
+
+/// Selects inputs and change for a spend whose outputs are already set, runs
+/// through the checklist if there is one, and then signs and records it as
+/// `sign_and_record` does. Returns whether the wallet was saved.
+fn fund_and_sign<D: Dongle>(dongle: &mut D, wallet: &mut EncryptedWallet, filename: &str, spend: &mut Spend, fee_rate: u64,
+                            inputs: Vec<(Sha256dHash, u32)>, checklist: &Option<Vec<String>>, destinations: &[Address], json: bool) -> Result<bool, Failed> {
+    say!(json, "Scanning wallet to find funds and change...");
+    if inputs.is_empty() {
+        try_unwrap("Finding funds and change",
+                   wallet.get_inputs_and_change(dongle, fee_rate, spend))?;
+    } else {
+        let selection = CoinSelection::Explicit(inputs);
+        try_unwrap("Finding requested inputs and change",
+                   wallet.select_inputs_and_change(dongle, fee_rate, &selection, spend))?;
+    }
+
+    say!(json, "Spending {} inputs, paying a fee of {} satoshi.", spend.input.len(), spend.fee());
+    if spend.lock_time > 0 {
+        say!(json, "Transaction is locked to {}.", spend.lock_time);
+    }
+
+    if let Some(ref steps) = *checklist {
+        if !run_checklist(steps, json) {
+            say!(json, "Cancelled.");
+            return Err(Failed(exit_code::CANCELLED));
+        }
+    }
+
+    sign_and_record(dongle, wallet, filename, spend, destinations, json)
+}
+
+/// Signs a spend on the dongle, records the spent inputs and any change in
+/// the wallet, and saves the wallet once the user confirms they broadcast the
+/// transaction. Returns whether the wallet was saved. In JSON mode the result
+/// is printed as `{"txid":..., "hex":..., "saved":...}`.
+fn sign_and_record<D: Dongle>(dongle: &mut D, wallet: &mut EncryptedWallet, filename: &str, spend: &Spend, destinations: &[Address], json: bool) -> Result<bool, Failed> {
+    // The dongle shows every output but the change, and then the fee, for
+    // approval on its own screen. List the same here so the user has
+    // something to check the screen against.
+    say!(json, "The dongle will ask you to approve each of these outputs and the fee:");
+    for (vout, output) in spend.output.iter().enumerate() {
+        if spend.change_amount > 0 && vout == spend.change_vout as usize {
+            say!(json, "  output {}: {} satoshi change to entry {} (not shown by the dongle)",
+                 vout, output.value, spend.change_path[4] & 0x7fffffff);
+        } else {
+            say!(json, "  output {}: {}", vout, describe_output(output, destinations));
+        }
+    }
+    say!(json, "  fee: {} satoshi", spend.fee());
+    say!(json, "Reject on the dongle if anything it shows differs from this list.");
+
+    // Build transaction
+    let mut tx = Transaction {
+        version: 1,
+        lock_time: spend.lock_time,
+        input: Vec::with_capacity(spend.input.len()),
+        output: spend.output.clone(),
+    };
+
+    // Obtain signatures for it
+    for (n, input) in spend.input.iter().enumerate() {
+        say!(json, "Signing for input {} of {}...", n + 1, spend.input.len());
+        let mut txin = input.txin.clone();
+        txin.script_sig = try_unwrap("Signing for input",
+                                     wallet.get_script_sig(dongle, spend, input.index, n > 0))?;
+        tx.input.push(txin);
+    }
+
+    // Update all affected entries
+    for input in &spend.input {
+        say!(json, "Marking entry {} as spent", input.index);
+        try_unwrap("Marking spent",
+                   wallet.mark_spent(dongle, input.index))?;
+    }
+    // Update change
+    if spend.change_amount > 0 {
+        say!(json, "Recording change output as used. We need a bit of information.");
+        let name = user_prompt("Your name");
+        let block_str = user_prompt("Recent blockhash (pick one say, 20 blocks ago, that is unlikely to be reorged out)");
+        let block = check_parse(Sha256dHash::from_hex(&block_str), "decoding blockhash hex")?;
+        if block.len() != 32 {
+            say!(json, "A blockhash must be 32 bytes (64 hex characters)");
+            return Err(Failed(exit_code::USAGE));
+        }
+        let index = (spend.change_path[4] & 0x7fffffff) as usize;
+        let entry = try_unwrap("Updating change entry",
+                               wallet.update(dongle, index, name, block, Update::Change(&tx, spend.change_vout)))?;
+        say!(json, "{}", entry);
+    }
+
+    say!(json, "Processing this as a receive to self-spends.");
+    try_unwrap("Processing transaction",
+               wallet.receive(dongle, &tx))?;
+
+    let tx_hex = bitcoin_serialize_hex(&tx).unwrap();
+    say!(json, "Please `sendrawtransaction` the following transaction {}", tx_hex);
+    let yes = user_prompt("If this succeeded type YES to saveout the wallet.");
+    let saved = if yes == "YES" {
+        // Rerandomize
+        try_unwrap("Rerandomizing wallet",
+                   wallet.rerandomize(dongle))?;
+
+        try_unwrap("Saving wallet",
+                   wallet.save(filename))?;
+        say!(json, "Done.");
+        true
+    } else {
+        say!(json, "Cancelled.");
+        false
+    };
+    if json {
+        println!("{{\"txid\":\"{}\",\"hex\":\"{}\",\"saved\":{}}}", tx.txid(), tx_hex, saved);
+    }
+    Ok(saved)
+}
+
+/// Reads and decrypts the payment queue kept beside a wallet, which is
+/// empty if the queue file does not exist
+fn read_queue<D: Dongle>(dongle: &mut D, wallet: &EncryptedWallet, queue_name: &str) -> Result<Vec<u8>, Failed> {
+    let mut data = vec![];
+    match fs::File::open(queue_name) {
+        Ok(mut fh) => try_unwrap("Reading payment queue", fh.read_to_end(&mut data).map_err(Error::from))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return try_unwrap("Opening payment queue", Err(Error::from(e)))
+    };
+    try_unwrap("Decrypting payment queue", wallet.decrypt_queue(dongle, &data))
+}
+
+/// Config file read from the user's home directory if `--config` is not
+/// given. It is never read from the current directory, since a config file
+/// can redirect the dongle connection.
+const DEFAULT_CONFIG_FILE: &'static str = ".icebox.toml";
+
+/// Path to the per-user config file, if the home directory is known
+fn default_config_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(DEFAULT_CONFIG_FILE))
+}
+
+/// Config file keys, each of which supplies a default for the command-line
+/// option of the same name
+const CONFIG_KEYS: &'static [&'static str] = &[
+    "json", "dongle", "timeout", "deadline", "passphrase",
+    "checklist", "checklist-file", "flag-above", "dust-below",
+];
+
+/// Config file keys for options which take no value, and so must be given
+/// as `true` or `false`
+const CONFIG_FLAGS: &'static [&'static str] = &["json", "passphrase", "checklist"];
+
+/// Reads a config file of `key = value` lines, a subset of TOML, and adds the
+/// corresponding options to `args` unless they were given on the command line.
+/// Values may be quoted strings or bare numbers, and must be `true` or `false`
+/// for options that take no value.
+fn apply_config(args: &mut Vec<String>, path: &Path) {
+    let filename = path.display();
+    let fh = match fs::File::open(path) {
+        Ok(fh) => fh,
+        Err(e) => {
+            eprintln!("Could not open config file {}: {}", filename, e);
+            process::exit(exit_code::USAGE);
+        }
+    };
+    eprintln!("Reading options from config file {}", filename);
+    for (n, line) in io::BufReader::new(fh).lines().enumerate() {
+        let line = pretty_unwrap("Reading config file", line.map_err(Error::from));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = match parts.next() {
+            Some(value) => value.trim().trim_matches('"'),
+            None => {
+                eprintln!("Line {} of config file {} is not of the form `key = value`.", n + 1, filename);
+                process::exit(exit_code::USAGE);
+            }
+        };
+        if !CONFIG_KEYS.contains(&key) {
+            eprintln!("Unknown key {} in config file {}.", key, filename);
+            process::exit(exit_code::USAGE);
+        }
+
+        let flag = format!("--{}", key);
+        if args.contains(&flag) {
+            continue;
+        }
+        if CONFIG_FLAGS.contains(&key) {
+            match value {
+                "true" => args.push(flag),
+                "false" => {}
+                _ => {
+                    eprintln!("Key {} in config file {} must be true or false, not {}.", key, filename, value);
+                    process::exit(exit_code::USAGE);
+                }
+            }
+        } else {
+            args.push(flag);
+            args.push(value.to_owned());
+        }
+    }
+}
+
+/// A command which failed, having already reported why, with the code to
+/// exit with if it was run on its own rather than from the shell
+struct Failed(i32);
+
 /// Prints the usage information and then halts the program
 fn usage_and_die(name: &str) -> ! {
-    println!("Usage: {} <wallet filename> <command>", name);
-    println!("  {} <filename> init <account> <n_entries>", name);
-    println!("  {} <filename> init-testnet <account> <n_entries>", name);
-    println!("  {} <filename> extend <new n_entries>", name);
-    println!("  {} <filename> rerandomize", name);
-    println!("");
-    println!("  {} <filename> getaddress [address index]", name);
-    println!("  {} <filename> getbalance", name);
-    println!("  {} <filename> info [address|index]", name);
-    println!("  {} <filename> signmessage [address|index] [message]", name);
-    println!("  {} <filename> receive <hex tx>", name);
-    println!("");
-    println!("  {} <filename> sendto <feerate> <destination> <amount> [<destination> <amount>...]", name);
-    println!("");
-    println!("All Bitcoin amounts should be specified in satoshi. No decimals.");
-    println!("The feerate is given in satoshis per kilobyte.");
-    println!("");
-    println!("Note that several commands do a linear scan of the entire wallet,");
-    println!("since dongle cooperation is required to decrypt each individual");
-    println!("entry. These commands will be very slow.");
-    // TODO: extend wallet
-    process::exit(1);
+    process::exit(usage(name).0);
+}
+
+/// Prints the usage information to stderr, returning the failure to report
+fn usage(name: &str) -> Failed {
+    eprintln!("Usage: {} <wallet filename> <command>", name);
+    eprintln!("  {} <filename> init <account> <n_entries>", name);
+    eprintln!("  {} <filename> init-testnet <account> <n_entries>", name);
+    eprintln!("  {} <filename> extend <new n_entries>", name);
+    eprintln!("  {} <filename> rerandomize", name);
+    eprintln!("  {} <filename> setpassphrase", name);
+    eprintln!("  {} <filename> hashwallet", name);
+    eprintln!("  {} <filename> reverify", name);
+    eprintln!("  {} <filename> check              reverify, and report on the file checksum", name);
+    eprintln!("  {} <filename> backup <backup filename>", name);
+    eprintln!("  {} <filename> restore <backup filename>", name);
+    eprintln!("  {} <filename> recover <output filename>", name);
+    eprintln!("");
+    eprintln!("  {} <filename> getaddress [address index]", name);
+    eprintln!("  {} <filename> getbalance", name);
+    eprintln!("  {} <filename> info [address|index]", name);
+    eprintln!("  {} <filename> addressinfo <address>", name);
+    eprintln!("  {} <filename> checkentries", name);
+    eprintln!("  {} <filename> signmessage <address|index> <message...>", name);
+    eprintln!("  {} <filename> receive <hex tx>", name);
+    eprintln!("  {} <filename> attest", name);
+    eprintln!("  {} <filename> export [--format csv] <output file>", name);
+    eprintln!("  {} <filename> exportdescriptors <output file>", name);
+    eprintln!("");
+    eprintln!("  {} <filename> sendto <feerate> <destination> <amount> [<destination> <amount>...]", name);
+    eprintln!("  {} <filename> sendto --template <template file> [<total amount>]", name);
+    eprintln!("  {} <filename> consolidate <feerate> <below amount>", name);
+    eprintln!("  {} <filename> queuepayment <destination> <amount>", name);
+    eprintln!("  {} <filename> flushqueue <feerate>          pay everything queued by", name);
+    eprintln!("                            queuepayment in one transaction. The queue is kept");
+    eprintln!("                            encrypted in <filename>.queue; delete that file to");
+    eprintln!("                            drop every queued payment");
+    eprintln!("");
+    eprintln!("  {} <filename> shell                 read further commands from stdin, one", name);
+    eprintln!("                            per line, keeping the dongle session open; options");
+    eprintln!("                            given here apply to each command not giving them");
+    eprintln!("");
+    eprintln!("  {} verify message <address> <signature> <message>", name);
+    eprintln!("  {} verifymessage <address> <signature> <message>", name);
+    eprintln!("  {} verify attestation <address> <signature> <txid:vout:amount>...", name);
+    eprintln!("  {} serve <address:port>          relay APDUs from `--dongle tcp:` to this dongle;", name);
+    eprintln!("                            the address must be loopback unless --allow-remote");
+    eprintln!("                            is given, since anyone who can connect can use it");
+    eprintln!("");
+    eprintln!("Global options:");
+    eprintln!("  --config <file>           read default options from <file> (default");
+    eprintln!("                            $HOME/{} if it exists; never a file in", DEFAULT_CONFIG_FILE);
+    eprintln!("                            the current directory). Each line is `key = value`");
+    eprintln!("                            where key is an option name without the dashes,");
+    eprintln!("                            one of: {}.", CONFIG_KEYS.join(", "));
+    eprintln!("                            Options given on the command line take priority.");
+    eprintln!("  --json                    print the result of each command as JSON; other");
+    eprintln!("                            output, errors and all prompts go to stderr");
+    eprintln!("  --dongle <transport>      `hid` (default) for an attached device, or");
+    eprintln!("                            `tcp:<host>:<port>` for a Speculos emulator or");
+    eprintln!("                            `{} serve`, e.g. through an SSH tunnel", name);
+    eprintln!("  --passphrase              prompt for the wallet passphrase, for wallets");
+    eprintln!("                            created or set up with one");
+    eprintln!("  --timeout <seconds>       how long to wait for each dongle reply (default {})",
+             DEFAULT_TIMEOUT_SECS);
+    eprintln!("  --deadline <seconds>      stop talking to the dongle after this long; the");
+    eprintln!("                            wallet file is left as last saved");
+    eprintln!("");
+    eprintln!("Options for receive:");
+    eprintln!("  --flag-above <amount>     require review of receives larger than <amount>");
+    eprintln!("  --dust-below <amount>     require review of receives smaller than <amount>");
+    eprintln!("                            (default {})", RECEIVE_DUST);
+    eprintln!("");
+    eprintln!("Options for sendto and flushqueue:");
+    eprintln!("  --checklist               walk through a checklist before signing");
+    eprintln!("  --checklist-file <file>   as --checklist, reading one step per line from <file>");
+    eprintln!("  --input <txid>:<vout>     spend exactly this output; may be repeated, and");
+    eprintln!("                            disables automatic coin selection");
+    eprintln!("");
+    eprintln!("Options for sendto:");
+    eprintln!("  --template <file>         take destinations, amounts and feerate from a spend");
+    eprintln!("                            template; `pay <address> <percent>%` lines are");
+    eprintln!("                            taken as a percentage of <total amount>");
+    eprintln!("  --save-template <file>    save the destinations and feerate as a template");
+    eprintln!("  --data <hex>              add an OP_RETURN output carrying up to {} bytes", MAX_DATA_BYTES);
+    eprintln!("");
+    eprintln!("Options for sendto, flushqueue and consolidate:");
+    eprintln!("  --height <height>         the current block height; the transaction's");
+    eprintln!("                            locktime is set near it to discourage fee sniping");
+    eprintln!("  --locktime <locktime>     set the transaction's locktime exactly");
+    eprintln!("");
+    eprintln!("All Bitcoin amounts should be specified in satoshi. No decimals.");
+    eprintln!("The feerate is given in satoshis per kilobyte.");
+    eprintln!("Entry dates are recorded and shown in UTC. Entries written by older");
+    eprintln!("versions are shown with the local offset they were written with.");
+    eprintln!("");
+    eprintln!("Note that several commands do a linear scan of the entire wallet,");
+    eprintln!("since dongle cooperation is required to decrypt each individual");
+    eprintln!("entry. These commands will be very slow.");
+    eprintln!("");
+    eprintln!("Exit codes: 0 success, 1 other error, 2 bad usage, 3 dongle not found,");
+    eprintln!("4 dongle error, 5 refused on dongle, 6 dongle locked, 7 bad wallet file,");
+    eprintln!("8 insufficient funds, 9 policy violation, 10 cancelled by user,");
+    eprintln!("11 verification failed, 12 file already exists, 13 timed out.");
+    Failed(exit_code::USAGE)
 }
 
 /// In case of error, prints a friendly version of an error message and then
@@ -83,75 +527,371 @@ fn usage_and_die(name: &str) -> ! {
 fn pretty_unwrap<T>(msg: &str, res: Result<T, Error>) -> T {
     match res {
         Ok(r) => r,
-        Err(error) => {
-            print!("{}: ", msg);
-            match error {
-                // Several APDU statuses can be fixed withuser intervention
-                Error::ApduBadStatus(sw::BAD_LENGTH) => {
-                    println!("We sent a bad length to the dongle. This is a bug.");
-                }
-                Error::ApduBadStatus(sw::BAD_DATA) => {
-                    println!("We sent bad data to the dongle. This is a bug.");
-                }
-                Error::ApduBadStatus(sw::BAD_P1_OR_P2) => {
-                    println!("We sent a bad P1 or P2 to the dongle. This is a bug.");
-                }
-                Error::ApduBadStatus(sw::INS_NOT_SUPPORTED) => {
-                    println!("Device did not understand something. Are you running the BTC app?");
-                }
-                Error::ApduBadStatus(sw::exception::EXCEPTION) => {
-                    println!("The dongle app threw an exception.");
-                }
-                Error::ApduBadStatus(sw::exception::HALTED) => {
-                    println!("The dongle app has halted and will refuse all further messages until it is restarted.");
-                }
-                Error::ApduBadStatus(sw::DONGLE_LOCKED) => {
-                    println!("Please unlock the dongle.");
-                }
-                Error::ApduBadStatus(sw::SIGN_REFUSED) => {
-                    println!("User refused the signature on the dongle.");
-                }
-                // Otherwise just print the error
-                e => println!("{}", e)
+        Err(error) => process::exit(report_error(msg, error))
+    }
+}
+
+/// As `pretty_unwrap`, but returns the failure rather than halting, so that
+/// the shell can carry on
+fn try_unwrap<T>(msg: &str, res: Result<T, Error>) -> Result<T, Failed> {
+    res.map_err(|error| Failed(report_error(msg, error)))
+}
+
+/// Prints a friendly version of an error message to stderr, returning the
+/// exit code for it
+fn report_error(msg: &str, error: Error) -> i32 {
+    let code = error.exit_code();
+    eprint!("{}: ", msg);
+    match error {
+        // Several APDU statuses can be fixed withuser intervention
+        Error::ApduBadStatus(sw::BAD_LENGTH) => {
+            eprintln!("We sent a bad length to the dongle. This is a bug.");
+        }
+        Error::ApduBadStatus(sw::BAD_DATA) => {
+            eprintln!("We sent bad data to the dongle. This is a bug.");
+        }
+        Error::ApduBadStatus(sw::BAD_P1_OR_P2) => {
+            eprintln!("We sent a bad P1 or P2 to the dongle. This is a bug.");
+        }
+        Error::ApduBadStatus(sw::INS_NOT_SUPPORTED) => {
+            eprintln!("Device did not understand something. Are you running the BTC app?");
+            eprintln!("(Versions 2.x of the app may not support the legacy protocol ICBOC uses.)");
+        }
+        Error::ApduBadStatus(sw::exception::EXCEPTION) => {
+            eprintln!("The dongle app threw an exception.");
+        }
+        Error::ApduBadStatus(sw::exception::HALTED) => {
+            eprintln!("The dongle app has halted and will refuse all further messages until it is restarted.");
+        }
+        Error::ApduBadStatus(sw::DONGLE_LOCKED) => {
+            eprintln!("Please unlock the dongle.");
+        }
+        Error::ApduBadStatus(sw::SIGN_REFUSED) => {
+            eprintln!("User refused the signature on the dongle.");
+        }
+        Error::ApduBadStatus(sw::TECHNICAL_PROBLEM) => {
+            eprintln!("The serving end of `--dongle tcp:` could not reach its dongle. See its log.");
+        }
+        Error::WrongDongle => {
+            eprintln!("The wallet did not decrypt with this dongle. Either it was created");
+            eprintln!("with a different dongle or seed, or the wallet file is corrupted.");
+        }
+        Error::WrongPassphrase => {
+            eprintln!("The wallet did not decrypt with this passphrase. Either the passphrase");
+            eprintln!("is wrong, or the wallet was created with a different dongle or seed.");
+        }
+        Error::EntryCorrupted(index) => {
+            eprintln!("Entry {} did not decrypt to valid data, though the wallet does", index);
+            eprintln!("belong to this dongle. The wallet file is probably corrupted.");
+        }
+        // Otherwise just print the error
+        e => eprintln!("{}", e)
+    }
+    code
+}
+
+/// Options which only affect a single command. In the shell they are given
+/// afresh on each line, and those given to `shell` itself are defaults.
+#[derive(Clone)]
+struct CommandOptions {
+    checklist: Option<Vec<String>>,
+    flag_above: Option<u64>,
+    dust_below: Option<u64>,
+    locktime: Option<u32>,
+    height: Option<u32>,
+    data: Option<Vec<u8>>,
+    template: Option<String>,
+    inputs: Vec<(Sha256dHash, u32)>,
+    save_template: Option<String>
+}
+
+/// Removes the per-command options from the argument list
+fn parse_command_options(args: &mut Vec<String>, name: &str) -> Result<CommandOptions, Failed> {
+    let checklist = match take_option(args, "--checklist-file", name)? {
+        Some(file) => {
+            let fh = try_unwrap("Opening checklist file", fs::File::open(&file).map_err(Error::from))?;
+            let lines = try_unwrap("Reading checklist file",
+                                   io::BufReader::new(fh).lines().collect::<Result<Vec<String>, io::Error>>().map_err(Error::from))?;
+            let steps: Vec<String> = lines.into_iter().filter(|line| !line.trim().is_empty()).collect();
+            Some(steps)
+        }
+        None => if take_flag(args, "--checklist") {
+            Some(DEFAULT_CHECKLIST.iter().map(|s| s.to_string()).collect::<Vec<String>>())
+        } else {
+            None
+        }
+    };
+
+    let flag_above = take_parsed(args, "--flag-above", name)?;
+    let dust_below = take_parsed(args, "--dust-below", name)?;
+    let locktime = take_parsed(args, "--locktime", name)?;
+    let height = take_parsed(args, "--height", name)?;
+    if locktime.is_some() && height.is_some() {
+        eprintln!("At most one of --locktime and --height may be given.");
+        return Err(Failed(exit_code::USAGE));
+    }
+    let data = match take_option(args, "--data", name)? {
+        Some(s) => {
+            let data: Vec<u8> = check_parse(hex::FromHex::from_hex(s.as_bytes()), "decoding --data hex")?;
+            if data.len() > MAX_DATA_BYTES {
+                eprintln!("--data may be at most {} bytes, not {}", MAX_DATA_BYTES, data.len());
+                return Err(Failed(exit_code::USAGE));
             }
-            process::exit(1);
+            Some(data)
+        }
+        None => None
+    };
+    let template = take_option(args, "--template", name)?;
+    let mut inputs = vec![];
+    while let Some(input) = take_option(args, "--input", name)? {
+        let parts: Vec<&str> = input.split(':').collect();
+        if parts.len() != 2 {
+            eprintln!("Inputs must be given as <txid>:<vout>, not {}", input);
+            return Err(Failed(exit_code::USAGE));
+        }
+        let txid = check_parse(Sha256dHash::from_hex(parts[0]), "decoding txid hex")?;
+        let vout = parse_arg(parts[1], "Parsing vout as number")?;
+        inputs.push((txid, vout));
+    }
+    let save_template = take_option(args, "--save-template", name)?;
+
+    Ok(CommandOptions {
+        checklist: checklist,
+        flag_above: flag_above,
+        dust_below: dust_below,
+        locktime: locktime,
+        height: height,
+        data: data,
+        template: template,
+        inputs: inputs,
+        save_template: save_template
+    })
+}
+
+impl CommandOptions {
+    /// Fills in any option not given here from `defaults`. The locktime and
+    /// height are taken together, since at most one of them may be given.
+    fn or(self, defaults: &CommandOptions) -> CommandOptions {
+        let (locktime, height) = if self.locktime.is_some() || self.height.is_some() {
+            (self.locktime, self.height)
+        } else {
+            (defaults.locktime, defaults.height)
+        };
+        CommandOptions {
+            checklist: self.checklist.or_else(|| defaults.checklist.clone()),
+            flag_above: self.flag_above.or(defaults.flag_above),
+            dust_below: self.dust_below.or(defaults.dust_below),
+            locktime: locktime,
+            height: height,
+            data: self.data.or_else(|| defaults.data.clone()),
+            template: self.template.or_else(|| defaults.template.clone()),
+            inputs: if self.inputs.is_empty() { defaults.inputs.clone() } else { self.inputs },
+            save_template: self.save_template.or_else(|| defaults.save_template.clone())
         }
     }
 }
 
 fn main() {
     // Startup
-    simplelog::SimpleLogger::init(simplelog::LogLevelFilter::Info, simplelog::Config::default()).unwrap();
+    let mut args: Vec<String> = env::args().collect();
+    let name = args.get(0).cloned().unwrap_or(String::new());
+    match exit_on_failure(take_option(&mut args, "--config", &name)) {
+        Some(file) => apply_config(&mut args, Path::new(&file)),
+        None => if let Some(path) = default_config_path() {
+            if fs::metadata(&path).is_ok() {
+                apply_config(&mut args, &path);
+            }
+        }
+    }
+    let json = take_flag(&mut args, "--json");
+    let allow_remote = take_flag(&mut args, "--allow-remote");
+    // In JSON mode stdout is reserved for the result, so only log errors (to stderr)
+    let log_level = if json { simplelog::LogLevelFilter::Error } else { simplelog::LogLevelFilter::Info };
+    simplelog::SimpleLogger::init(log_level, simplelog::Config::default()).unwrap();
+
+    let passphrase = if take_flag(&mut args, "--passphrase") {
+        Some(passphrase_prompt("Wallet passphrase"))
+    } else {
+        None
+    };
+    let passphrase = passphrase.as_ref().map(String::as_str);
+    let opts = exit_on_failure(parse_command_options(&mut args, &name));
+    let transport = exit_on_failure(take_option(&mut args, "--dongle", &name)).unwrap_or("hid".to_owned());
+    if transport != "hid" && !transport.starts_with("tcp:") {
+        eprintln!("--dongle must be `hid` or `tcp:<host>:<port>`, not {}", transport);
+        process::exit(exit_code::USAGE);
+    }
+    let timeout = exit_on_failure(take_parsed(&mut args, "--timeout", &name)).unwrap_or(DEFAULT_TIMEOUT_SECS);
+    if timeout == 0 {
+        eprintln!("--timeout must be at least 1 second.");
+        process::exit(exit_code::USAGE);
+    }
+    let deadline = exit_on_failure(take_parsed(&mut args, "--deadline", &name))
+                       .map(|secs| Instant::now() + Duration::from_secs(secs));
 
-    let args: Vec<String> = env::args().collect();
     match args.len() {
         0 => usage_and_die(""),
         1 | 2 => usage_and_die(&args[0]),
         _ => {}
     }
 
+    // Verification only needs public data, so do it before looking for a dongle
+    if args[1] == "verify" {
+        verify_and_exit(&args);
+    }
+    if args[1] == "verifymessage" {
+        args[1] = "verify".to_owned();
+        args.insert(2, "message".to_owned());
+        verify_and_exit(&args);
+    }
+
+    // Hold the wallet's lock until we are done with it. The OS releases it
+    // when we exit, including through process::exit.
+    let _lock = if args[1] == "serve" {
+        None
+    } else {
+        Some(exit_on_failure(lock_wallet(&args)))
+    };
+
     // Contact device and run GET FIRMWARE to sanity check it
-    let mut dongle = pretty_unwrap("Finding dongle", icebox::dongle::ledger::get_unique());
-    println!("Successfully found dongle {:?}", dongle.product());
-    let version = pretty_unwrap("Getting firmware version",
-                                dongle.get_firmware_version());
-    println!("Firmware version {}.{}.{}", version.major_version, version.minor_version, version.patch_version);
+    let mut dongle = pretty_unwrap("Finding dongle", icebox::dongle::open(&transport));
+    dongle.set_timeouts(Duration::from_secs(timeout), deadline);
+    let version = pretty_unwrap("Checking firmware version",
+                                dongle.check_app_version());
+    if !json {
+        println!("Successfully found dongle {:?}", dongle.product());
+        println!("Firmware version {}.{}.{}", version.major_version, version.minor_version, version.patch_version);
+    }
+
+    // Relaying APDUs for a remote icebox does not involve any wallet file
+    if args[1] == "serve" {
+        say!(json, "Relaying APDUs received at {} to the dongle. Interrupt to stop.", args[2]);
+        pretty_unwrap("Serving dongle", icebox::dongle::speculos::serve(&mut dongle, &args[2], allow_remote));
+        return;
+    }
+
+    if args[2] == "shell" {
+        run_shell(&mut dongle, &args, json, passphrase, &opts);
+    } else {
+        exit_on_failure(run_command(&mut dongle, &args, json, passphrase, opts));
+    }
+}
+
+/// Commands run against a wallet file, as matched by `run_command`
+const WALLET_COMMANDS: &'static [&'static str] = &[
+    "init", "init-testnet", "extend", "info", "recover", "checkentries", "addressinfo",
+    "signmessage", "getaddress", "getbalance", "export", "exportdescriptors", "attest",
+    "receive", "rerandomize", "setpassphrase", "backup", "restore", "hashwallet", "sendto",
+    "queuepayment", "flushqueue", "reverify", "check", "consolidate", "shell",
+];
+
+/// Commands which may create the wallet file. The shell is among them since
+/// any command may be run from it.
+const CREATING_COMMANDS: &'static [&'static str] = &["init", "init-testnet", "restore", "shell"];
+
+/// Takes the lock for the wallet file named in `args`, once the command is
+/// known to use it. Unless the command creates the wallet, the file must
+/// already exist, so that no lock file is left beside a mistyped name.
+fn lock_wallet(args: &[String]) -> Result<WalletLock, Failed> {
+    let command = &args[2][..];
+    if !WALLET_COMMANDS.contains(&command) {
+        return Err(usage(&args[0]));
+    }
+    if !CREATING_COMMANDS.contains(&command) {
+        try_unwrap("Opening wallet", fs::metadata(&args[1]).map_err(Error::from))?;
+    }
+    try_unwrap("Locking wallet", WalletLock::acquire(&args[1]))
+}
+
+/// Halts with the failure's exit code if a command failed
+fn exit_on_failure<T>(res: Result<T, Failed>) -> T {
+    match res {
+        Ok(r) => r,
+        Err(Failed(code)) => process::exit(code)
+    }
+}
+
+/// Reads commands from stdin, one per line, and runs each against the same
+/// dongle session until `exit` or end of input. Arguments containing spaces
+/// may be quoted as in a POSIX shell. A command which fails is reported, and
+/// the session carries on with the next one. The prompt goes to stderr, so
+/// that stdout carries only command output. Options given to `shell` itself
+/// apply to each command which does not give them.
+fn run_shell(dongle: &mut AnyDongle, args: &[String], json: bool, passphrase: Option<&str>, defaults: &CommandOptions) {
+    let stdin = io::stdin();
+    loop {
+        eprint!("icebox> ");
+        io::stderr().flush().expect("flushing stderr");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).expect("reading from stdin") == 0 {
+            eprintln!("");
+            break;
+        }
+
+        let mut line_args = vec![args[0].clone(), args[1].clone()];
+        match split_command_line(&line) {
+            Some(split) => line_args.extend(split),
+            None => {
+                eprintln!("Unterminated quote or trailing backslash.");
+                continue;
+            }
+        }
+        if line_args.len() < 3 {
+            continue;
+        }
+        match &line_args[2][..] {
+            "exit" | "quit" => break,
+            "shell" => {
+                eprintln!("Already in the shell.");
+                continue;
+            }
+            _ => {}
+        }
+        let res = parse_command_options(&mut line_args, &args[0]).and_then(|opts| {
+            run_command(dongle, &line_args, json, passphrase, opts.or(defaults))
+        });
+        if let Err(Failed(code)) = res {
+            eprintln!("Command failed (exit code {}).", code);
+        }
+    }
+}
+
+/// Runs a single command against an already-opened dongle
+fn run_command(dongle: &mut AnyDongle, args: &[String], json: bool, passphrase: Option<&str>, opts: CommandOptions) -> Result<(), Failed> {
+    let CommandOptions { checklist, flag_above, dust_below, locktime, height, data, template, inputs, save_template } = opts;
+    let dust_below = dust_below.unwrap_or(RECEIVE_DUST);
+
+    // Given the current height, lock spends to it to discourage fee sniping.
+    // Like Bitcoin Core, one time in ten go back up to 100 blocks further so
+    // that delayed transactions do not stand out.
+    let lock_time = match (locktime, height) {
+        (Some(locktime), _) => locktime,
+        (None, Some(height)) => {
+            let random = try_unwrap("Getting random bytes", dongle.get_random(2))?;
+            if random[0] < 26 {
+                height.saturating_sub(random[1] as u32 % 100)
+            } else {
+                height
+            }
+        }
+        (None, None) => 0
+    };
 
     // Decide what to do
     match &args[2][..] {
         // Create a new wallet
         "init" | "init-testnet" => {
             if args.len() < 5 {
-                usage_and_die(&args[0]);
+                return Err(usage(&args[0]));
             }
 
             let filename = &args[1];
-            let account = u32::from_str(&args[3]).expect("Parsing account as number");
-            let entries = usize::from_str(&args[4]).expect("Parsing n_entries as number");
+            let account = parse_arg(&args[3], "Parsing account as number")?;
+            let entries = parse_arg(&args[4], "Parsing n_entries as number")?;
 
             if fs::metadata(filename).is_ok() {
-                println!("File {} already exists. Please move it out of the way to initialize a new wallet.", filename);
-                process::exit(1);
+                eprintln!("File {} already exists. Please move it out of the way to initialize a new wallet.", filename);
+                return Err(Failed(exit_code::FILE_EXISTS));
             }
 
             let network;
@@ -161,123 +901,273 @@ fn main() {
                 network = Network::Bitcoin;
             }
 
-            let wallet = pretty_unwrap("Creating wallet",
-                                       icebox::wallet::EncryptedWallet::new(&mut dongle, network, account, entries));
-            pretty_unwrap("Saving wallet",
-                          wallet.save(filename));
+            if let Some(passphrase) = passphrase {
+                if passphrase_prompt("Repeat wallet passphrase") != passphrase {
+                    eprintln!("Passphrases did not match.");
+                    return Err(Failed(exit_code::CANCELLED));
+                }
+            }
+
+            let wallet = try_unwrap("Creating wallet",
+                                    icebox::wallet::EncryptedWallet::new(dongle, network, account, entries, passphrase))?;
+            try_unwrap("Saving wallet",
+                       wallet.save(filename))?;
         }
         // Extend wallet capacity
         "extend" => {
             if args.len() < 4 {
-                usage_and_die(&args[0]);
+                return Err(usage(&args[0]));
             }
 
             let filename = &args[1];
-            let n_entries = usize::from_str(&args[3]).expect("Parsing n_entries as number");
+            let n_entries = parse_arg(&args[3], "Parsing n_entries as number")?;
 
-            let mut wallet = pretty_unwrap("Loading wallet",
-                                           icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
             if wallet.n_entries() >= n_entries {
-                println!("Wallet already has {} entries, not decreasing.", wallet.n_entries());
+                say!(json, "Wallet already has {} entries, not decreasing.", wallet.n_entries());
             } else {
-                pretty_unwrap("Extending wallet",
-                              wallet.extend(&mut dongle, n_entries));
+                // Extend in chunks, saving after each, so that an interrupted
+                // extension keeps the entries already encrypted
+                say!(json, "Progress is saved every {} entries; if interrupted, re-run this command to resume.", EXTEND_CHUNK_SIZE);
+                while wallet.n_entries() < n_entries {
+                    let target = cmp::min(wallet.n_entries() + EXTEND_CHUNK_SIZE, n_entries);
+                    try_unwrap("Extending wallet",
+                               wallet.extend(dongle, target))?;
+                    try_unwrap("Saving wallet",
+                               wallet.save(filename))?;
+                }
+            }
+            try_unwrap("Saving wallet",
+                       wallet.save(filename))?;
+            if json {
+                println!("{{\"entries\":{}}}", wallet.n_entries());
             }
-            pretty_unwrap("Saving wallet",
-                          wallet.save(filename));
         }
         // Get information about the wallet or a specific entry
         "info" => {
             if args.len() < 3 {
-                usage_and_die(&args[0]);
+                return Err(usage(&args[0]));
             }
 
             let filename = &args[1];
-            let wallet = pretty_unwrap("Loading wallet",
-                                       icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
-            println!("Wallet: {} entries, account {}.", wallet.n_entries(), wallet.account());
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            if !json {
+                println!("Wallet: {} entries, account {}.", wallet.n_entries(), wallet.account());
+            }
             if args.len() > 3 {
                 // An index > length 10 is an address, we scan for it
-                if args[3].len() > 10 {
-                    let entry = pretty_unwrap("Searching for entry",
-                                              wallet.search(&mut dongle, &args[3]));
-                    println!("{}", entry);
-                    if entry.state == EntryState::Valid {
-                        pretty_unwrap("Confirming address",
-                                      wallet.display(&mut dongle, entry.index));
-                    }
+                let entry = if args[3].len() > 10 {
+                    try_unwrap("Searching for entry",
+                               wallet.search(dongle, &args[3]))?
                 } else {
                 // Otherwise take the index as an index
-                    let index = usize::from_str(&args[3]).expect("Parsing index as number");
-                    let entry = pretty_unwrap("Decrypting entry",
-                                              wallet.lookup(&mut dongle, index));
+                    let index = parse_arg(&args[3], "Parsing index as number")?;
+                    try_unwrap("Decrypting entry",
+                               wallet.lookup(dongle, index))?
+                };
+                if json {
+                    println!("{{\"entries\":{},\"account\":{},\"entry\":{}}}", wallet.n_entries(), wallet.account(), entry.to_json());
+                } else {
                     println!("{}", entry);
-                    if entry.state == EntryState::Valid {
-                        pretty_unwrap("Confirming address",
-                                      wallet.display(&mut dongle, entry.index));
-                    }
                 }
+                if entry.state == EntryState::Valid {
+                    try_unwrap("Confirming address",
+                               wallet.display(dongle, entry.index))?;
+                }
+            } else if json {
+                println!("{{\"entries\":{},\"account\":{}}}", wallet.n_entries(), wallet.account());
+            }
+        }
+        // Salvage what can be read of a damaged wallet into a new file
+        "recover" => {
+            if args.len() < 4 {
+                return Err(usage(&args[0]));
+            }
+            let filename = &args[1];
+            let output = &args[3];
+            if fs::metadata(output).is_ok() {
+                eprintln!("File {} already exists. Please move it out of the way first.", output);
+                return Err(Failed(exit_code::FILE_EXISTS));
+            }
+
+            let (wallet, replaced, bad_signature) = try_unwrap("Recovering wallet",
+                                                               EncryptedWallet::recover(dongle, filename, passphrase))?;
+            let replaced: Vec<String> = replaced.iter().map(|i| i.to_string()).collect();
+            let bad_signature: Vec<String> = bad_signature.iter().map(|i| i.to_string()).collect();
+            say!(json, "Salvaged {} of {} entries.", wallet.n_entries() - replaced.len(), wallet.n_entries());
+            if !replaced.is_empty() {
+                say!(json, "Replaced unreadable entries {} with entries marked as issued.", replaced.join(", "));
+                say!(json, "Any coins received at these addresses must be re-added with `receive`.");
+            }
+            if !bad_signature.is_empty() {
+                say!(json, "Kept entries {}, which decrypt but have bad signatures, unchanged.", bad_signature.join(", "));
+                say!(json, "They cannot be spent from. Run `{} {} reverify` for details.", args[0], output);
+            }
+            try_unwrap("Saving recovered wallet", wallet.save(output))?;
+            if json {
+                println!("{{\"file\":{},\"entries\":{},\"replaced\":[{}],\"bad_signature\":[{}]}}",
+                         json_string(output), wallet.n_entries(), replaced.join(","), bad_signature.join(","));
+            } else {
+                println!("Wrote recovered wallet to {}. {} is unchanged.", output, filename);
+            }
+        }
+        // Summarize how much of the wallet is used
+        "checkentries" => {
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let (mut unused, mut issued, mut unspent, mut spent, mut invalid) = (0, 0, 0, 0, 0);
+            let mut highest_used = None;
+            for i in 0..wallet.n_entries() {
+                let entry = try_unwrap("Decrypting entry", wallet.lookup(dongle, i))?;
+                match entry.state {
+                    EntryState::Unused => unused += 1,
+                    EntryState::Valid => issued += 1,
+                    EntryState::Received => if entry.spent { spent += 1 } else { unspent += 1 },
+                    EntryState::Invalid => invalid += 1
+                }
+                if entry.state != EntryState::Unused {
+                    highest_used = Some(i);
+                }
+            }
+            // Unused entries below the highest used one are skipped, not lost:
+            // `getaddress <index>` can still hand them out
+            let remaining = match highest_used {
+                Some(index) => wallet.n_entries() - index - 1,
+                None => wallet.n_entries()
+            };
+            let skipped = unused - remaining;
+
+            if json {
+                let highest = highest_used.map(|i| i.to_string()).unwrap_or("null".to_owned());
+                println!("{{\"entries\":{},\"unused\":{},\"issued\":{},\"unspent\":{},\"spent\":{},\"invalid\":{},\"highest_used\":{},\"remaining\":{},\"skipped\":{}}}",
+                         wallet.n_entries(), unused, issued, unspent, spent, invalid, highest, remaining, skipped);
+            } else {
+                println!("Entries:             {}", wallet.n_entries());
+                println!("  unused:            {}", unused);
+                println!("  issued, no funds:  {}", issued);
+                println!("  received, unspent: {}", unspent);
+                println!("  received, spent:   {}", spent);
+                println!("  bad signature:     {}", invalid);
+                match highest_used {
+                    Some(index) => println!("Highest used index:  {}", index),
+                    None => println!("Highest used index:  none")
+                }
+                println!("Unused after it:     {}", remaining);
+                println!("Skipped below it:    {}", skipped);
+                if invalid > 0 {
+                    println!("Warning: {} entries have bad signatures. Run `{} {} reverify` for details.", invalid, args[0], filename);
+                }
+                if remaining < LOW_ENTRIES_WARNING {
+                    println!("Warning: only {} entries remain. Consider running", remaining);
+                    println!("`{} {} extend <new n_entries>` before the wallet runs out.", args[0], filename);
+                }
+            }
+        }
+        // Show where an address comes from, for importing it elsewhere
+        "addressinfo" => {
+            if args.len() < 4 {
+                return Err(usage(&args[0]));
+            }
+
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let entry = try_unwrap("Searching for entry",
+                                   wallet.search(dongle, &args[3]))?;
+            let fingerprint = try_unwrap("Getting master fingerprint",
+                                         master_fingerprint(dongle))?;
+            // Strip the leading `m` to give a key origin like `[d34db33f/44'/0'/...]`
+            let origin = format!("[{}{}]", fingerprint.to_hex(), &bip32_path_string(&entry.bip32_path)[1..]);
+            if json {
+                println!("{{\"origin\":\"{}\",\"entry\":{}}}", origin, entry.to_json());
+            } else {
+                println!("  origin: {}", origin);
+                println!("{}", entry);
             }
         }
         // Sign a message with a specific entry
         "signmessage" => {
             if args.len() < 5 {
-                usage_and_die(&args[0]);
+                return Err(usage(&args[0]));
             }
 
             let filename = &args[1];
-            let wallet = pretty_unwrap("Loading wallet",
-                                       icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
             // An index > length 10 is an address, we scan for it
             let entry = if args[3].len() > 10 {
-                pretty_unwrap("Searching for entry", wallet.search(&mut dongle, &args[3]))
+                try_unwrap("Searching for entry", wallet.search(dongle, &args[3]))?
             } else {
             // Otherwise take the index as an index
-                let index = usize::from_str(&args[3]).expect("Parsing index as number");
-                pretty_unwrap("Decrypting entry", wallet.lookup(&mut dongle, index))
+                let index = parse_arg(&args[3], "Parsing index as number")?;
+                try_unwrap("Decrypting entry", wallet.lookup(dongle, index))?
             };
-            let sig = pretty_unwrap("Getting signature", entry.sign_message(&mut dongle, &args[4]));
-            let sig64 = pretty_unwrap("Encoding sig as base64", convert_compact_to_signmessage_rpc(&sig[..]));
-            println!("{}", entry.address);
-            println!("{}", sig64);
+            // Take the rest of the line as the message, so it need not be quoted
+            let message = args[4..].join(" ");
+            let sig = try_unwrap("Getting signature", entry.sign_message(dongle, &message))?;
+            let sig64 = try_unwrap("Encoding sig as base64", convert_compact_to_signmessage_rpc(&sig[..]))?;
+            // Check the signature recovers to the address before handing it out
+            if !try_unwrap("Verifying signature", verify_signmessage(&entry.address, &sig64, message.as_bytes()))? {
+                eprintln!("The dongle's signature does NOT verify for {}. Not using it.", entry.address);
+                return Err(Failed(exit_code::VERIFY_FAILED));
+            }
+            if json {
+                println!("{{\"address\":\"{}\",\"signature\":\"{}\"}}", entry.address, sig64);
+            } else {
+                println!("{}", entry.address);
+                println!("{}", sig64);
+            }
         }
         // Update a new unused address slot
         "getaddress" => {
             if args.len() < 3 {
-                usage_and_die(&args[0]);
+                return Err(usage(&args[0]));
             }
 
             let filename = &args[1];
-            let mut wallet = pretty_unwrap("Loading wallet",
-                                           icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
             let index;
             if args.len() > 3 {
-                index = usize::from_str(&args[3]).expect("Parsing index as number");
+                index = parse_arg(&args[3], "Parsing index as number")?;
             } else {
-                println!("Scanning for next unused address. This may take a while.");
-                index = pretty_unwrap("Finding next unused address",
-                                      wallet.next_unused_index(&mut dongle));
+                say!(json, "Scanning for next unused address. This may take a while.");
+                index = try_unwrap("Finding next unused address",
+                                   wallet.next_unused_index(dongle))?;
             }
 
-            let entry = pretty_unwrap("Decrypting entry",
-                                      wallet.lookup(&mut dongle, index));
+            let entry = try_unwrap("Decrypting entry",
+                                   wallet.lookup(dongle, index))?;
             if entry.state == icebox::wallet::EntryState::Unused {
                 let name = user_prompt("Your name");
                 let block_str = user_prompt("Recent blockhash (pick one say, 20 blocks ago, that is unlikely to be reorged out)");
-                let block = Sha256dHash::from_hex(&block_str).expect("decoding blockhash hex");
+                let block = check_parse(Sha256dHash::from_hex(&block_str), "decoding blockhash hex")?;
                 let note = user_prompt("Note to tag address with");
 
-                let entry = pretty_unwrap("Updating entry",
-                                          wallet.update(&mut dongle, index, name, block, Update::Unused(note)));
-                println!("{}", entry);
-                pretty_unwrap("Confirming address",
-                              wallet.display(&mut dongle, index));
-                println!("Rerandomizing wallet...");
-                pretty_unwrap("Rerandomizing wallet",
-                              wallet.rerandomize(&mut dongle));
-                println!("Done. Saving.");
-                pretty_unwrap("Saving wallet",
-                              wallet.save(filename));
+                let entry = try_unwrap("Updating entry",
+                                       wallet.update(dongle, index, name, block, Update::Unused(note)))?;
+                say!(json, "{}", entry);
+                try_unwrap("Confirming address",
+                           wallet.display(dongle, index))?;
+                let remaining = wallet.n_entries() - index - 1;
+                if remaining < LOW_ENTRIES_WARNING {
+                    say!(json, "Warning: only {} entries remain after this one. Consider running", remaining);
+                    say!(json, "`{} {} extend <new n_entries>` before the wallet runs out.", args[0], filename);
+                }
+                say!(json, "Rerandomizing wallet...");
+                try_unwrap("Rerandomizing wallet",
+                           wallet.rerandomize(dongle))?;
+                say!(json, "Done. Saving.");
+                try_unwrap("Saving wallet",
+                           wallet.save(filename))?;
+                if json {
+                    println!("{{\"issued\":true,\"entry\":{}}}", entry.to_json());
+                }
+            } else if json {
+                println!("{{\"issued\":false,\"entry\":{}}}", entry.to_json());
             } else {
                 println!("This address has already been used.");
             }
@@ -285,53 +1175,324 @@ fn main() {
         // Sum all unspent entries to determine current wallet balance
         "getbalance" => {
             let filename = &args[1];
-            let wallet = pretty_unwrap("Loading wallet",
-                                       icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
-            let balance = pretty_unwrap("Checking balance",
-                                        wallet.get_balance(&mut dongle));
-            println!("Balance: {}", balance);
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let balance = try_unwrap("Checking balance",
+                                     wallet.get_balance(dongle))?;
+            if json {
+                println!("{{\"balance\":{}}}", balance);
+            } else {
+                println!("Balance: {}", balance);
+            }
+        }
+        // Write every receive out as CSV for accounting software
+        "export" => {
+            let (format, output) = match args.len() {
+                4 => ("csv", &args[3]),
+                6 if args[3] == "--format" => (&args[4][..], &args[5]),
+                _ => return Err(usage(&args[0]))
+            };
+            if format != "csv" {
+                eprintln!("Unsupported export format {}; only csv is supported.", format);
+                return Err(Failed(exit_code::USAGE));
+            }
+            if fs::metadata(output).is_ok() {
+                eprintln!("File {} already exists. Please move it out of the way first.", output);
+                return Err(Failed(exit_code::FILE_EXISTS));
+            }
+
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let mut received = vec![];
+            for i in 0..wallet.n_entries() {
+                let entry = try_unwrap("Decrypting entry", wallet.lookup(dongle, i))?;
+                if entry.state == EntryState::Received {
+                    received.push(entry);
+                }
+            }
+            received.sort_by_key(|entry| (entry.timestamp(), entry.index));
+
+            let mut fh = try_unwrap("Creating export file", fs::File::create(output).map_err(Error::from))?;
+            try_unwrap("Writing export file", writeln!(fh, "{}", ENTRY_CSV_HEADER).map_err(Error::from))?;
+            let mut total = 0;
+            for entry in &received {
+                total += entry.amount;
+                try_unwrap("Writing export file", writeln!(fh, "{}", entry.to_csv(total)).map_err(Error::from))?;
+            }
+            if json {
+                println!("{{\"file\":{},\"exported\":{}}}", json_string(output), received.len());
+            } else {
+                println!("Exported {} receives to {}.", received.len(), output);
+            }
+        }
+        // Write descriptors for every issued address, for a watch-only copy in Bitcoin Core
+        "exportdescriptors" => {
+            if args.len() < 4 {
+                return Err(usage(&args[0]));
+            }
+            let output = &args[3];
+            if fs::metadata(output).is_ok() {
+                eprintln!("File {} already exists. Please move it out of the way first.", output);
+                return Err(Failed(exit_code::FILE_EXISTS));
+            }
+
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let fingerprint = try_unwrap("Getting master key fingerprint",
+                                         master_fingerprint(dongle))?;
+            let mut descriptors = vec![];
+            for i in 0..wallet.n_entries() {
+                let entry = try_unwrap("Decrypting entry", wallet.lookup(dongle, i))?;
+                match entry.state {
+                    EntryState::Unused => continue,
+                    EntryState::Invalid => {
+                        say!(json, "Skipping entry {}, which has a bad signature.", i);
+                        continue;
+                    }
+                    EntryState::Valid | EntryState::Received => {}
+                }
+                let key = try_unwrap("Getting public key",
+                                     dongle.get_public_key(&entry.bip32_path, false))?;
+                match entry.to_descriptor(&key.public_key, &fingerprint) {
+                    Some(desc) => {
+                        // Nothing can have paid an address before it was handed out
+                        let timestamp = entry.timestamp().map(|t| t.to_string()).unwrap_or("\"now\"".to_owned());
+                        descriptors.push(format!("  {{\"desc\":{},\"timestamp\":{},\"label\":{}}}",
+                                                 json_string(&desc), timestamp,
+                                                 json_string(entry.note.trim_right_matches('\0'))));
+                    }
+                    None => say!(json, "Skipping entry {}, whose address does not match its key (run reverify).", i)
+                }
+            }
+
+            let mut fh = try_unwrap("Creating descriptor file", fs::File::create(output).map_err(Error::from))?;
+            try_unwrap("Writing descriptor file",
+                       write!(fh, "[\n{}\n]\n", descriptors.join(",\n")).map_err(Error::from))?;
+            if json {
+                println!("{{\"file\":{},\"exported\":{}}}", json_string(output), descriptors.len());
+            } else {
+                println!("Exported {} descriptors to {}.", descriptors.len(), output);
+                println!("Load them into a watch-only Bitcoin Core wallet with `importdescriptors`.");
+            }
+        }
+        // Sign a commitment to the current set of unspent outputs
+        "attest" => {
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let (outputs, commitment) = try_unwrap("Committing to unspent outputs",
+                                                   wallet.utxo_commitment(dongle))?;
+            let commitment_hex = commitment.to_hex();
+            let (address, sig) = try_unwrap("Signing attestation",
+                                            wallet.sign_attestation(dongle, &commitment_hex))?;
+            let sig64 = try_unwrap("Encoding sig as base64", convert_compact_to_signmessage_rpc(&sig[..]))?;
+
+            let total: u64 = outputs.iter().map(|&(_, _, amount)| amount).sum();
+            if json {
+                let outputs: Vec<String> = outputs.iter().map(|&(ref txid, vout, amount)| {
+                    format!("{{\"txid\":\"{}\",\"vout\":{},\"amount\":{}}}", txid, vout, amount)
+                }).collect();
+                println!("{{\"outputs\":[{}],\"total\":{},\"commitment\":\"{}\",\"address\":\"{}\",\"signature\":\"{}\"}}",
+                         outputs.join(","), total, commitment_hex, address, sig64);
+            } else {
+                println!("Unspent outputs:");
+                for &(ref txid, vout, amount) in &outputs {
+                    println!("  {}:{} {}", txid, vout, amount);
+                }
+                println!("     total: {}", total);
+                println!("commitment: {}", commitment_hex);
+                println!("   address: {}", address);
+                println!(" signature: {}", sig64);
+                println!("");
+                println!("To verify, recompute the commitment as the SHA256 of each outpoint (32-byte");
+                println!("txid in internal byte order, 4-byte LE vout) followed by its 8-byte LE amount,");
+                println!("sorted by outpoint bytes, then run");
+                println!("    bitcoin-cli verifymessage {} {} {}", address, sig64, commitment_hex);
+                println!("or, to do both steps at once,");
+                println!("    {} verify attestation {} {} <txid:vout:amount>...", args[0], address, sig64);
+            }
         }
         // Process a transaction that sends us coins
         "receive" => {
             if args.len() < 3 {
-                usage_and_die(&args[0]);
+                return Err(usage(&args[0]));
             }
 
             let filename = &args[1];
-            let mut wallet = pretty_unwrap("Loading wallet",
-                                           icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
-            let tx_bytes: Vec<u8> = hex::FromHex::from_hex(args[3].as_bytes()).expect("decoding tx hex");
-            let tx: Transaction = bitcoin_deserialize(&tx_bytes).expect("decoding transaction");
-
-            println!("Processing transaction...");
-            pretty_unwrap("Processing transaction",
-                          wallet.receive(&mut dongle, &tx));
-            println!("Rerandomizing wallet...");
-            pretty_unwrap("Rerandomizing wallet",
-                          wallet.rerandomize(&mut dongle));
-            println!("Done. Saving.");
-            pretty_unwrap("Saving wallet",
-                          wallet.save(filename));
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let tx_bytes: Vec<u8> = check_parse(hex::FromHex::from_hex(args[3].as_bytes()), "decoding tx hex")?;
+            let tx: Transaction = check_parse(bitcoin_deserialize(&tx_bytes), "decoding transaction")?;
+
+            if !json {
+                println!("Processing transaction...");
+            }
+            let updated = try_unwrap("Processing transaction",
+                                     wallet.receive(dongle, &tx))?;
+
+            // Screen the new receives and make the user sign off on anything unusual
+            let mut flagged = false;
+            for index in &updated {
+                let entry = try_unwrap("Decrypting entry",
+                                       wallet.lookup(dongle, *index))?;
+                let mut reasons = vec![];
+                if entry.amount < dust_below {
+                    reasons.push(format!("amount {} is below the dust threshold {}", entry.amount, dust_below));
+                }
+                if let Some(limit) = flag_above {
+                    if entry.amount > limit {
+                        reasons.push(format!("amount {} is above the review threshold {}", entry.amount, limit));
+                    }
+                }
+                if tx.is_coin_base() {
+                    reasons.push("output is from a coinbase transaction, which cannot be spent for 100 blocks".to_owned());
+                }
+                for reason in &reasons {
+                    warn!("REVIEW REQUIRED for entry {}: {}", index, reason);
+                    eprintln!("REVIEW REQUIRED for entry {}: {}", index, reason);
+                }
+                flagged |= !reasons.is_empty();
+            }
+            if flagged && user_prompt("Type YES to record these receives anyway") != "YES" {
+                say!(json, "Cancelled.");
+                return Err(Failed(exit_code::CANCELLED));
+            }
+            if !json {
+                println!("Rerandomizing wallet...");
+            }
+            try_unwrap("Rerandomizing wallet",
+                       wallet.rerandomize(dongle))?;
+            if !json {
+                println!("Done. Saving.");
+            }
+            try_unwrap("Saving wallet",
+                       wallet.save(filename))?;
+            if json {
+                let indices: Vec<String> = updated.iter().map(|i| i.to_string()).collect();
+                println!("{{\"txid\":\"{}\",\"updated\":[{}]}}", tx.txid(), indices.join(","));
+            }
         }
         // Re-encrypt the whole wallet to hide what has changed
         "rerandomize" => {
             let filename = &args[1];
-            let mut wallet = pretty_unwrap("Loading wallet",
-                                           icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
-            pretty_unwrap("Rerandomizing wallet",
-                          wallet.rerandomize(&mut dongle));
-            pretty_unwrap("Saving wallet",
-                          wallet.save(filename));
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            try_unwrap("Rerandomizing wallet",
+                       wallet.rerandomize(dongle))?;
+            try_unwrap("Saving wallet",
+                       wallet.save(filename))?;
+        }
+        // Change, add or remove the passphrase layered into entry keys
+        "setpassphrase" => {
+            let filename = &args[1];
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let new_passphrase = passphrase_prompt("New wallet passphrase (empty to remove the passphrase)");
+            if passphrase_prompt("Repeat new wallet passphrase") != new_passphrase {
+                eprintln!("Passphrases did not match.");
+                return Err(Failed(exit_code::CANCELLED));
+            }
+            let new_passphrase = if new_passphrase.is_empty() { None } else { Some(&new_passphrase[..]) };
+            try_unwrap("Re-encrypting wallet",
+                       wallet.set_passphrase(dongle, new_passphrase))?;
+            try_unwrap("Saving wallet",
+                       wallet.save(filename))?;
+            if json {
+                println!("{{\"passphrase\":{}}}", new_passphrase.is_some());
+            }
+        }
+        // Write a passphrase-encrypted backup of the decrypted wallet
+        "backup" => {
+            if args.len() < 4 {
+                return Err(usage(&args[0]));
+            }
+
+            let filename = &args[1];
+            let backup_filename = &args[3];
+            if fs::metadata(backup_filename).is_ok() {
+                eprintln!("File {} already exists. Please move it out of the way to write a backup.", backup_filename);
+                return Err(Failed(exit_code::FILE_EXISTS));
+            }
+
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let passphrase = passphrase_prompt("Backup passphrase");
+            if passphrase_prompt("Repeat passphrase") != passphrase {
+                eprintln!("Passphrases did not match.");
+                return Err(Failed(exit_code::CANCELLED));
+            }
+            say!(json, "Decrypting wallet. This may take a while.");
+            try_unwrap("Writing backup",
+                       wallet.backup(dongle, &passphrase, backup_filename))?;
+            if json {
+                println!("{{\"file\":{},\"entries\":{}}}", json_string(backup_filename), wallet.n_entries());
+            }
+        }
+        // Recreate a wallet from a passphrase-encrypted backup
+        "restore" => {
+            if args.len() < 4 {
+                return Err(usage(&args[0]));
+            }
+
+            let filename = &args[1];
+            let backup_filename = &args[3];
+            if fs::metadata(filename).is_ok() {
+                eprintln!("File {} already exists. Please move it out of the way to restore a backup.", filename);
+                return Err(Failed(exit_code::FILE_EXISTS));
+            }
+
+            let passphrase = passphrase_prompt("Backup passphrase");
+            let wallet = try_unwrap("Restoring backup",
+                                    icebox::wallet::EncryptedWallet::restore(dongle, &passphrase, backup_filename))?;
+            try_unwrap("Saving wallet",
+                       wallet.save(filename))?;
+            if json {
+                println!("{{\"entries\":{}}}", wallet.n_entries());
+            } else {
+                println!("Restored {} entries. If this dongle does not have the seed the backup was made", wallet.n_entries());
+                println!("with, used entries will show invalid signatures and cannot be spent from.");
+            }
+        }
+        // Hash the decrypted wallet contents, for comparing wallet files
+        "hashwallet" => {
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let hash = try_unwrap("Hashing wallet",
+                                  wallet.logical_hash(dongle))?;
+            if json {
+                println!("{{\"hash\":\"{}\"}}", hash.to_hex());
+            } else {
+                println!("Wallet hash: {}", hash.to_hex());
+            }
         }
         // Spend money
         "sendto" =>{
-            if args.len() < 6 || args.len() % 2 == 1 {
-                usage_and_die(&args[0]);
+            if template.is_some() && save_template.is_some() {
+                eprintln!("--save-template cannot be combined with --template.");
+                return Err(usage(&args[0]));
+            }
+            let template = match template {
+                Some(file) => {
+                    let fh = try_unwrap("Opening spend template", fs::File::open(&file).map_err(Error::from))?;
+                    Some(try_unwrap("Reading spend template", SpendTemplate::read(io::BufReader::new(fh)))?)
+                }
+                None => None
+            };
+            match template {
+                Some(ref template) => if template.needs_total() && args.len() < 4 {
+                    return Err(usage(&args[0]));
+                },
+                None => if args.len() < 6 || args.len() % 2 == 1 {
+                    return Err(usage(&args[0]));
+                }
             }
 
             let filename = &args[1];
-            let mut wallet = pretty_unwrap("Loading wallet",
-                                           icebox::wallet::EncryptedWallet::load(&mut dongle, filename));
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
 
             // Assemble a "spend" object describing the transaction to be created
             let mut spend = Spend {
@@ -339,83 +1500,236 @@ fn main() {
                 change_path: [0; 5],
                 change_amount: 0,
                 change_vout: 0,
-                output: vec![]
+                output: vec![],
+                lock_time: lock_time
             };
-            let fee_rate = u64::from_str(&args[3]).expect("Parsing fee rate as number");
-            for i in 4..args.len() {
-                if i % 2 == 1 {
-                    continue;
+            let mut destinations = vec![];
+            let fee_rate;
+            if let Some(ref template) = template {
+                fee_rate = match template.fee_rate {
+                    Some(rate) => rate,
+                    None => {
+                        eprintln!("Spend template does not give a feerate.");
+                        return Err(Failed(exit_code::USAGE));
+                    }
+                };
+                let total = if template.needs_total() {
+                    parse_arg(&args[3], "Parsing total amount as number")?
+                } else {
+                    0
+                };
+                spend.output = try_unwrap("Instantiating spend template", template.instantiate(total))?;
+                destinations.extend(template.outputs.iter().map(|&(ref addr, _)| addr.clone()));
+                if let Some(ref memo) = template.memo {
+                    say!(json, "Template memo: {}", memo);
+                }
+            } else {
+                fee_rate = parse_arg(&args[3], "Parsing fee rate as number")?;
+                let mut outputs = vec![];
+                for i in 4..args.len() {
+                    if i % 2 == 1 {
+                        continue;
+                    }
+                    let addr: Address = parse_arg(&args[i], "Decoding address")?;
+                    let amount = parse_arg(&args[i + 1], "Parsing amount as number")?;
+                    if amount < DUST_LIMIT {
+                        return try_unwrap("Checking amount", Err(Error::OutputBelowDust(amount)));
+                    }
+                    spend.output.push(TxOut {
+                        value: amount,
+                        script_pubkey: addr.script_pubkey()
+                    });
+                    destinations.push(addr.clone());
+                    outputs.push((addr, TemplateAmount::Satoshi(amount)));
                 }
-                let addr = Address::from_str(&args[i]).expect("Decoding address");
-                let amount = u64::from_str(&args[i + 1]).expect("Parsing amount as number");
+                if let Some(ref file) = save_template {
+                    if fs::metadata(file).is_ok() {
+                        eprintln!("File {} already exists. Please move it out of the way to save a template.", file);
+                        return Err(Failed(exit_code::FILE_EXISTS));
+                    }
+                    let template = SpendTemplate {
+                        fee_rate: Some(fee_rate),
+                        memo: None,
+                        outputs: outputs
+                    };
+                    let fh = try_unwrap("Creating spend template", fs::File::create(file).map_err(Error::from))?;
+                    try_unwrap("Saving spend template", template.write(fh).map_err(Error::from))?;
+                    say!(json, "Saved spend template to {}.", file);
+                }
+            }
+            if let Some(ref data) = data {
+                say!(json, "Adding OP_RETURN output with data {}", data.to_hex());
                 spend.output.push(TxOut {
-                    value: amount,
-                    script_pubkey: addr.script_pubkey()
+                    value: 0,
+                    script_pubkey: script::Builder::new().push_opcode(opcodes::All::OP_RETURN)
+                                                         .push_slice(data)
+                                                         .into_script()
                 });
             }
-            println!("Scanning wallet to find funds and change...");
-            pretty_unwrap("Finding funds and change",
-                          wallet.get_inputs_and_change(&mut dongle, fee_rate, &mut spend));
+            fund_and_sign(dongle, &mut wallet, filename, &mut spend, fee_rate, inputs, &checklist, &destinations, json)?;
+        }
+        // Save a payment to be made later, batched with others
+        "queuepayment" => {
+            if args.len() != 5 {
+                return Err(usage(&args[0]));
+            }
+            let addr: Address = parse_arg(&args[3], "Decoding address")?;
+            let amount = parse_arg(&args[4], "Parsing amount as number")?;
+            // The queue is read back as a spend template, which rejects dust
+            if amount < DUST_LIMIT {
+                return try_unwrap("Checking amount", Err(Error::OutputBelowDust(amount)));
+            }
 
-            // Build transaction
-            let mut tx = Transaction {
-                version: 1,
-                lock_time: 0,
-                input: Vec::with_capacity(spend.input.len()),
-                output: spend.output.clone(),
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let queue_name = format!("{}.queue", filename);
+            let mut text = read_queue(dongle, &wallet, &queue_name)?;
+            let payment = SpendTemplate {
+                fee_rate: None,
+                memo: None,
+                outputs: vec![(addr, TemplateAmount::Satoshi(amount))]
             };
+            try_unwrap("Writing payment queue", payment.write(&mut text).map_err(Error::from))?;
 
-            // Obtain signatures for it
-            for (n, input) in spend.input.iter().enumerate() {
-                println!("Signing for input {} of {}...", n + 1, spend.input.len());
-                let mut txin = input.txin.clone();
-                txin.script_sig = pretty_unwrap("Signing for input",
-                                                wallet.get_script_sig(&mut dongle, &spend, input.index, n > 0));
-                tx.input.push(txin);
+            // Write the whole queue afresh, then rename it into place
+            let data = try_unwrap("Encrypting payment queue", wallet.encrypt_queue(dongle, &text))?;
+            let temp_name = format!("{}.0", queue_name);
+            try_unwrap("Writing payment queue",
+                       fs::File::create(&temp_name).and_then(|mut fh| { fh.write_all(&data)?; fh.sync_all() }).map_err(Error::from))?;
+            try_unwrap("Writing payment queue", fs::rename(&temp_name, &queue_name).map_err(Error::from))?;
+            if json {
+                println!("{{\"address\":\"{}\",\"amount\":{},\"queue\":{}}}", args[3], amount, json_string(&queue_name));
+            } else {
+                println!("Queued payment of {} satoshi to {} in {}.", amount, args[3], queue_name);
             }
-
-            // Update all affected entries
-            for input in &spend.input {
-                println!("Marking entry {} as spent", input.index);
-                pretty_unwrap("Marking spent",
-                              wallet.mark_spent(&mut dongle, input.index));
+        }
+        // Pay everything in the queue in a single transaction
+        "flushqueue" => {
+            if args.len() < 4 {
+                return Err(usage(&args[0]));
             }
-            // Update change
-            if spend.change_amount > 0 {
-                println!("Recording change output as used. We need a bit of information.");
-                let name = user_prompt("Your name");
-                let block_str = user_prompt("Recent blockhash (pick one say, 20 blocks ago, that is unlikely to be reorged out)");
-                let block = Sha256dHash::from_hex(&block_str).expect("decoding blockhash hex");
-                if block.len() != 32 {
-                    println!("A blockhash must be 32 bytes (64 hex characters)");
-                    process::exit(1);
-                }
-                let index = (spend.change_path[4] & 0x7fffffff) as usize;
-                let entry = pretty_unwrap("Updating change entry",
-                                          wallet.update(&mut dongle, index, name, block, Update::Change(&tx, spend.change_vout)));
-                println!("{}", entry);
+            let filename = &args[1];
+            let fee_rate = parse_arg(&args[3], "Parsing fee rate as number")?;
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let queue_name = format!("{}.queue", filename);
+            let text = read_queue(dongle, &wallet, &queue_name)?;
+            if text.is_empty() {
+                say!(json, "No payments queued.");
+                return Ok(());
+            }
+            let queue = try_unwrap("Reading payment queue", SpendTemplate::read(&text[..]))?;
+            let mut spend = Spend {
+                input: vec![],
+                change_path: [0; 5],
+                change_amount: 0,
+                change_vout: 0,
+                output: try_unwrap("Reading payment queue", queue.instantiate(0))?,
+                lock_time: lock_time
+            };
+            let destinations: Vec<Address> = queue.outputs.iter().map(|&(ref addr, _)| addr.clone()).collect();
+            say!(json, "Batching {} queued payments.", spend.output.len());
+            if fund_and_sign(dongle, &mut wallet, filename, &mut spend, fee_rate, inputs, &checklist, &destinations, json)? {
+                try_unwrap("Clearing payment queue", fs::remove_file(&queue_name).map_err(Error::from))?;
+                say!(json, "Cleared payment queue {}.", queue_name);
+            }
+        }
+        // Check every entry's signature and address
+        "reverify" | "check" => {
+            let filename = &args[1];
+            let wallet = try_unwrap("Loading wallet",
+                                    EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            if wallet.has_checksum() {
+                say!(json, "Wallet file checksum verified.");
+            } else {
+                say!(json, "Wallet file has no checksum; one will be added the next time it is saved.");
+            }
+            say!(json, "Checking all {} entries. This will take a while.", wallet.n_entries());
+            let problems = try_unwrap("Reverifying wallet", wallet.reverify(dongle))?;
+            if json {
+                let list: Vec<String> = problems.iter().map(|&(index, problem)| {
+                    format!("{{\"index\":{},\"problem\":{}}}", index, json_string(&problem.to_string()))
+                }).collect();
+                println!("{{\"checksum\":{},\"entries\":{},\"problems\":[{}]}}",
+                         wallet.has_checksum(), wallet.n_entries(), list.join(","));
+                if !problems.is_empty() {
+                    return Err(Failed(exit_code::VERIFY_FAILED));
+                }
+            } else if problems.is_empty() {
+                println!("All {} entries verified.", wallet.n_entries());
+            } else {
+                for &(index, problem) in &problems {
+                    println!("Entry {}: {}", index, problem);
+                }
+                println!("Found {} problems among {} entries.", problems.len(), wallet.n_entries());
+                return Err(Failed(exit_code::VERIFY_FAILED));
+            }
+        }
+        // Sweep small outputs into fresh change addresses
+        "consolidate" => {
+            if args.len() < 5 {
+                return Err(usage(&args[0]));
             }
 
-            println!("Processing this as a receive to self-spends.");
-            pretty_unwrap("Processing transaction",
-                          wallet.receive(&mut dongle, &tx));
+            let filename = &args[1];
+            let mut wallet = try_unwrap("Loading wallet",
+                                        EncryptedWallet::load_with_passphrase(dongle, filename, passphrase))?;
+            let fee_rate = parse_arg(&args[3], "Parsing fee rate as number")?;
+            let below = parse_arg(&args[4], "Parsing threshold as number")?;
 
-            println!("Please `sendrawtransaction` the following transaction {}", bitcoin_serialize_hex(&tx).unwrap());
-            let yes = user_prompt("If this succeeded type YES to saveout the wallet.");
-            if yes == "YES" {
-                // Rerandomize
-                pretty_unwrap("Rerandomizing wallet",
-                              wallet.rerandomize(&mut dongle));
+            // Each batch spends up to CONSOLIDATE_MAX_INPUTS outputs; once it is
+            // recorded those are marked spent, so the next scan finds the rest.
+            // Change from earlier batches is unconfirmed, and spending it again
+            // would chain transactions and pay its fee twice, so skip it.
+            let mut batch = 1;
+            let mut change_entries = vec![];
+            loop {
+                let selection = CoinSelection::Consolidate {
+                    below: below,
+                    max_inputs: CONSOLIDATE_MAX_INPUTS,
+                    exclude: change_entries.clone()
+                };
+                let mut spend = Spend {
+                    input: vec![],
+                    change_path: [0; 5],
+                    change_amount: 0,
+                    change_vout: 0,
+                    output: vec![],
+                    lock_time: lock_time
+                };
+                say!(json, "Scanning wallet for outputs below {} satoshi...", below);
+                match wallet.select_inputs_and_change(dongle, fee_rate, &selection, &mut spend) {
+                    // Whatever is left over after the first batch may just not
+                    // be worth consolidating
+                    Err(Error::InsufficientFunds(_, _)) if batch > 1 => {
+                        say!(json, "Nothing (more) to consolidate.");
+                        break;
+                    }
+                    res => try_unwrap("Finding outputs to consolidate", res)?
+                }
+                if spend.input.len() < 2 {
+                    say!(json, "Nothing (more) to consolidate.");
+                    break;
+                }
 
-                pretty_unwrap("Saving wallet",
-                              wallet.save(filename));
-                println!("Done.");
-            } else {
-                println!("Cancelled.");
+                say!(json, "Consolidation transaction {}: {} inputs into {} satoshi at entry {}, paying a fee of {} satoshi.",
+                     batch, spend.input.len(), spend.change_amount, spend.change_path[4] & 0x7fffffff, spend.fee());
+                if user_prompt("Type YES to sign this transaction") != "YES" {
+                    say!(json, "Cancelled.");
+                    return Err(Failed(exit_code::CANCELLED));
+                }
+                if !sign_and_record(dongle, &mut wallet, filename, &spend, &[], json)? {
+                    break;
+                }
+                if spend.change_amount > 0 {
+                    change_entries.push((spend.change_path[4] & 0x7fffffff) as usize);
+                }
+                batch += 1;
             }
         }
         // Don't recognize command
-        _ => usage_and_die(&args[0])
+        _ => return Err(usage(&args[0]))
     }
+    Ok(())
 }
-
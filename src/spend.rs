@@ -27,6 +27,8 @@ pub struct Input {
     pub index: usize,
     /// The "trusted input" that encodes the transaction amount to the Ledger
     pub trusted_input: [u8; 56],
+    /// The amount of the txout this input spends
+    pub amount: u64,
     /// The scriptpubkey of the txout this input spends
     pub script_pubkey: Script,
     /// The txin for this input, with blank script to be filled in
@@ -42,6 +44,7 @@ impl Input {
         Input {
             index: entry.index,
             trusted_input: trusted_input,
+            amount: entry.amount,
             script_pubkey: entry.address.script_pubkey(),
             txin: TxIn {
                 previous_output: OutPoint {
@@ -67,6 +70,17 @@ pub struct Spend {
     /// The amount to allocate to change
     pub change_amount: u64,
     /// A list of outputs, including the change one
-    pub output: Vec<TxOut>
+    pub output: Vec<TxOut>,
+    /// The nLockTime of the transaction
+    pub lock_time: u32
+}
+
+impl Spend {
+    /// The fee paid by this spend, i.e. its input amounts less its output amounts
+    pub fn fee(&self) -> u64 {
+        let input_total: u64 = self.input.iter().map(|input| input.amount).sum();
+        let output_total: u64 = self.output.iter().map(|output| output.value).sum();
+        input_total - output_total
+    }
 }
 
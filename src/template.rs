@@ -0,0 +1,215 @@
+// ICBOC
+// Written in 2017 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Spend Templates
+//!
+//! Text files describing a recurring payment, so that it need not be typed
+//! in afresh at every signing ceremony. Each non-empty line not starting
+//! with `#` is one of
+//!
+//! ```text
+//! feerate <satoshi per kilobyte>
+//! memo <free text>
+//! pay <address> <satoshi>
+//! pay <address> <percent>%
+//! ```
+//!
+//! Percentages are whole numbers and are taken of a total amount given
+//! when the template is instantiated. A template must have at least one
+//! `pay` line, and no output may be worth less than `DUST_LIMIT`.
+//!
+
+use bitcoin::{Address, TxOut};
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use constants::wallet::DUST_LIMIT;
+use error::Error;
+
+/// The amount paid to a single destination
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TemplateAmount {
+    /// A fixed number of satoshi
+    Satoshi(u64),
+    /// A whole-number percentage of the total
+    Percent(u64)
+}
+
+/// A named, reusable description of a spend
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpendTemplate {
+    /// Fee rate in satoshi per kilobyte, if the template fixes one
+    pub fee_rate: Option<u64>,
+    /// Note to display to the user before signing
+    pub memo: Option<String>,
+    /// Destinations and the amounts to pay them
+    pub outputs: Vec<(Address, TemplateAmount)>
+}
+
+impl SpendTemplate {
+    /// Parses a template from a reader
+    pub fn read<R: BufRead>(r: R) -> Result<SpendTemplate, Error> {
+        let mut ret = SpendTemplate {
+            fee_rate: None,
+            memo: None,
+            outputs: vec![]
+        };
+        let mut total_percent = 0;
+
+        for (n, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.splitn(2, ' ');
+            let keyword = words.next().unwrap();
+            let rest = words.next().unwrap_or("").trim();
+            match keyword {
+                "feerate" => {
+                    let rate = u64::from_str(rest).map_err(|_| Error::BadTemplate(n + 1))?;
+                    ret.fee_rate = Some(rate);
+                }
+                "memo" => ret.memo = Some(rest.to_owned()),
+                "pay" => {
+                    let parts: Vec<&str> = rest.split_whitespace().collect();
+                    if parts.len() != 2 {
+                        return Err(Error::BadTemplate(n + 1));
+                    }
+                    let addr = Address::from_str(parts[0]).map_err(|_| Error::BadTemplate(n + 1))?;
+                    let amount = if parts[1].ends_with('%') {
+                        let pct = u64::from_str(&parts[1][..parts[1].len() - 1]).map_err(|_| Error::BadTemplate(n + 1))?;
+                        total_percent += pct;
+                        if pct == 0 || total_percent > 100 {
+                            return Err(Error::BadTemplate(n + 1));
+                        }
+                        TemplateAmount::Percent(pct)
+                    } else {
+                        let sat = u64::from_str(parts[1]).map_err(|_| Error::BadTemplate(n + 1))?;
+                        if sat < DUST_LIMIT {
+                            return Err(Error::BadTemplate(n + 1));
+                        }
+                        TemplateAmount::Satoshi(sat)
+                    };
+                    ret.outputs.push((addr, amount));
+                }
+                _ => return Err(Error::BadTemplate(n + 1))
+            }
+        }
+        // With no destinations the spend would only pay change
+        if ret.outputs.is_empty() {
+            return Err(Error::EmptyTemplate);
+        }
+        Ok(ret)
+    }
+
+    /// Writes the template out in the form accepted by `read`
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        if let Some(ref memo) = self.memo {
+            writeln!(w, "memo {}", memo)?;
+        }
+        if let Some(rate) = self.fee_rate {
+            writeln!(w, "feerate {}", rate)?;
+        }
+        for &(ref addr, amount) in &self.outputs {
+            match amount {
+                TemplateAmount::Satoshi(sat) => writeln!(w, "pay {} {}", addr, sat)?,
+                TemplateAmount::Percent(pct) => writeln!(w, "pay {} {}%", addr, pct)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any outputs are given as a percentage, in which case a total
+    /// is needed to instantiate the template
+    pub fn needs_total(&self) -> bool {
+        self.outputs.iter().any(|&(_, amount)| match amount {
+            TemplateAmount::Percent(_) => true,
+            TemplateAmount::Satoshi(_) => false
+        })
+    }
+
+    /// Produces the outputs of a spend, taking percentages of `total`, and
+    /// failing if any percentage comes to less than `DUST_LIMIT`
+    pub fn instantiate(&self, total: u64) -> Result<Vec<TxOut>, Error> {
+        self.outputs.iter().map(|&(ref addr, amount)| {
+            let value = match amount {
+                TemplateAmount::Satoshi(sat) => sat,
+                TemplateAmount::Percent(pct) => match total.checked_mul(pct) {
+                    Some(product) => product / 100,
+                    None => return Err(Error::TemplateTotalTooLarge(total))
+                }
+            };
+            if value < DUST_LIMIT {
+                return Err(Error::OutputBelowDust(value));
+            }
+            Ok(TxOut {
+                value: value,
+                script_pubkey: addr.script_pubkey()
+            })
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_and_instantiate() {
+        let text = "# payroll\n\
+                    memo monthly payroll\n\
+                    feerate 20000\n\
+                    pay 1BitcoinEaterAddressDontSendf59kuE 100000\n\
+                    pay 1BitcoinEaterAddressDontSendf59kuE 25%\n";
+        let template = SpendTemplate::read(text.as_bytes()).unwrap();
+        assert_eq!(template.fee_rate, Some(20000));
+        assert_eq!(template.memo, Some("monthly payroll".to_owned()));
+        assert!(template.needs_total());
+
+        let outputs = template.instantiate(1000000).unwrap();
+        assert_eq!(outputs[0].value, 100000);
+        assert_eq!(outputs[1].value, 250000);
+        assert!(template.instantiate(u64::max_value() / 10).is_err());
+
+        let mut written = vec![];
+        template.write(&mut written).unwrap();
+        assert_eq!(SpendTemplate::read(&written[..]).unwrap(), template);
+    }
+
+    #[test]
+    fn reject_bad_lines() {
+        assert!(SpendTemplate::read("feerate lots\n".as_bytes()).is_err());
+        assert!(SpendTemplate::read("# nothing to pay\nfeerate 20000\n".as_bytes()).is_err());
+        assert!(SpendTemplate::read("sendto 1BitcoinEaterAddressDontSendf59kuE 1\n".as_bytes()).is_err());
+        assert!(SpendTemplate::read("pay 1BitcoinEaterAddressDontSendf59kuE 60%\n\
+                                     pay 1BitcoinEaterAddressDontSendf59kuE 60%\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn reject_dust() {
+        assert!(SpendTemplate::read("pay 1BitcoinEaterAddressDontSendf59kuE 0\n".as_bytes()).is_err());
+        assert!(SpendTemplate::read("pay 1BitcoinEaterAddressDontSendf59kuE 545\n".as_bytes()).is_err());
+        assert!(SpendTemplate::read("pay 1BitcoinEaterAddressDontSendf59kuE 0%\n".as_bytes()).is_err());
+        assert!(SpendTemplate::read("pay 1BitcoinEaterAddressDontSendf59kuE 546\n".as_bytes()).is_ok());
+
+        let template = SpendTemplate::read("pay 1BitcoinEaterAddressDontSendf59kuE 10%\n".as_bytes()).unwrap();
+        match template.instantiate(5000) {
+            Err(Error::OutputBelowDust(500)) => {}
+            other => panic!("10% of 5000 instantiated as {:?}", other)
+        }
+        assert_eq!(template.instantiate(5460).unwrap()[0].value, 546);
+    }
+}
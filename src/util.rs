@@ -15,16 +15,156 @@
 //! # Miscellaneous Functions
 
 use base64;
-use bitcoin::{Transaction, Script, VarInt};
+use bitcoin::{Address, Transaction, Script, VarInt};
 use bitcoin::network::encodable::ConsensusEncodable;
 use bitcoin::network::serialize::RawEncoder;
 use crypto::digest::Digest;
+use crypto::ripemd160;
 use crypto::sha2;
-use secp256k1::{Secp256k1, Signature, SecretKey};
+use secp256k1::{self, Secp256k1, Signature, SecretKey, RecoverableSignature, RecoveryId};
 
 use spend::Spend;
 use error::Error;
 
+/// Encode a string as a quoted JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c)
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// Encode a string as a CSV field, quoting it only if it contains a comma,
+/// quote or line break
+pub fn csv_field(s: &str) -> String {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Split a command line into arguments the way a POSIX shell would, minus
+/// expansions: whitespace separates arguments except inside single or double
+/// quotes, and a backslash escapes the next character outside single quotes.
+/// Returns None if a quote is left open or the line ends in a backslash.
+pub fn split_command_line(line: &str) -> Option<Vec<String>> {
+    let mut ret = vec![];
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return None
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => arg.push(c),
+                            Some(c) => { arg.push('\\'); arg.push(c); }
+                            None => return None
+                        },
+                        Some(c) => arg.push(c),
+                        None => return None
+                    }
+                }
+            }
+            '\\' => {
+                in_arg = true;
+                match chars.next() {
+                    Some(c) => arg.push(c),
+                    None => return None
+                }
+            }
+            c if c.is_whitespace() => if in_arg {
+                ret.push(arg);
+                arg = String::new();
+                in_arg = false;
+            },
+            c => {
+                in_arg = true;
+                arg.push(c);
+            }
+        }
+    }
+    if in_arg {
+        ret.push(arg);
+    }
+    Some(ret)
+}
+
+/// Compute the checksum of an output descriptor, as defined in BIP 380, or
+/// None if the descriptor contains characters not allowed in descriptors
+pub fn descriptor_checksum(desc: &str) -> Option<String> {
+    const INPUT_CHARSET: &'static str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+    const CHECKSUM_CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATOR: [u64; 5] = [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd];
+
+    fn polymod(c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        let mut ret = ((c & 0x7ffffffff) << 5) ^ val;
+        for (i, gen) in GENERATOR.iter().enumerate() {
+            if (c0 >> i) & 1 == 1 {
+                ret ^= *gen;
+            }
+        }
+        ret
+    }
+
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in desc.chars() {
+        let pos = match INPUT_CHARSET.find(ch) {
+            Some(pos) => pos as u64,
+            None => return None
+        };
+        c = polymod(c, pos & 31);
+        class = class * 3 + (pos >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    let mut ret = String::with_capacity(8);
+    for j in 0..8 {
+        ret.push(CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char);
+    }
+    Some(ret)
+}
+
 /// Compute the SHA256 of some slice
 pub fn hash_sha256(input: &[u8]) -> [u8; 32] {
     let mut result = [0; 32];
@@ -34,6 +174,15 @@ pub fn hash_sha256(input: &[u8]) -> [u8; 32] {
     result
 }
 
+/// Compute the RIPEMD160 of the SHA256 of some slice
+pub fn hash160(input: &[u8]) -> [u8; 20] {
+    let mut result = [0; 20];
+    let mut hasher = ripemd160::Ripemd160::new();
+    hasher.input(&hash_sha256(input));
+    hasher.result(&mut result);
+    result
+}
+
 // The returned signature format is a bit funny. It is ASN.1 according to
 // the docs, but the first byte, which is uniformly 0x30 (SEQUENCE OF) in
 // libsecp, is alternately 0x30 (SEQUENCE OF) or 0x31 (SET OF). Further,
@@ -62,15 +211,19 @@ const NEG_ONE: [u8; 32] = [
 /// necessary. This does NOT result in BIP66-compliant low-s signatures,
 /// it merely makes the public nonce correspond to the encoded `r` value
 /// in a consistent way.
-///
-/// This may panic on bad input.
 pub fn convert_ledger_der_to_compact(sig: &[u8]) -> Result<[u8; 64], Error> {
+    if sig.len() < 6 {
+        return Err(Error::BadSignature);
+    }
     let r_len = sig[3] as usize;
+    if r_len > 33 || 6 + r_len > sig.len() {
+        return Err(Error::BadSignature);
+    }
     let s_len = sig[5 + r_len] as usize;
     let r_off = 4;
     let s_off = 6 + r_len;
 
-    if s_len > 32 || r_len > 33 {
+    if s_len > 32 || s_off + s_len > sig.len() {
         return Err(Error::BadSignature);
     }
 
@@ -125,6 +278,55 @@ pub fn convert_compact_to_signmessage_rpc(sig: &[u8]) -> Result<String, Error> {
     Ok(base64::encode(&ret))
 }
 
+/// Verifies a base64-encoded signature, as produced by `signmessage` in
+/// Bitcoin Core or `convert_compact_to_signmessage_rpc`, against a p2pkh
+/// address. Returns false if the signature is well-formed but was made
+/// by a different key.
+pub fn verify_signmessage(address: &Address, sig64: &str, message: &[u8]) -> Result<bool, Error> {
+    let sig = base64::decode(sig64).map_err(|_| Error::BadSignature)?;
+    if sig.len() != 65 || sig[0] < 27 || sig[0] > 34 {
+        return Err(Error::BadSignature);
+    }
+    let compressed = sig[0] >= 31;
+    let recid = RecoveryId::from_i32(((sig[0] - 27) & 3) as i32)?;
+
+    // Bitcoin signed messages are the double-SHA256 of a fixed prefix and
+    // the varint-prefixed message
+    let mut msg_full = b"\x18Bitcoin Signed Message:\n".to_vec();
+    if message.len() < 0xfd {
+        msg_full.push(message.len() as u8);
+    } else if message.len() <= 0xffff {
+        msg_full.push(0xfd);
+        msg_full.push(message.len() as u8);
+        msg_full.push((message.len() >> 8) as u8);
+    } else {
+        return Err(Error::Unsupported);
+    }
+    msg_full.extend(message);
+    let msg_hash = hash_sha256(&hash_sha256(&msg_full));
+
+    let secp = Secp256k1::verification_only();
+    let msg = secp256k1::Message::from_slice(&msg_hash)?;
+    let rsig = RecoverableSignature::from_compact(&secp, &sig[1..65], recid)?;
+    let pk = secp.recover(&msg, &rsig)?;
+    let pk_hash = if compressed {
+        hash160(&pk.serialize()[..])
+    } else {
+        hash160(&pk.serialize_uncompressed()[..])
+    };
+
+    Ok(p2pkh_script(&pk_hash) == address.script_pubkey())
+}
+
+/// Builds the p2pkh scriptpubkey paying to a given key hash
+pub fn p2pkh_script(pk_hash: &[u8; 20]) -> Script {
+    let mut spk = vec![0x76, 0xa9, 0x14];
+    spk.extend(pk_hash);
+    spk.push(0x88);
+    spk.push(0xac);
+    Script::from(spk)
+}
+
 /// Transactions are sent to the device in a bit of a weird way. Each individual
 /// transaction component needs to be sent to the device intact (except possibly
 /// scripts), but any transaction will greatly exceed the APDU packet size (260
@@ -230,7 +432,59 @@ pub fn encode_spend_outputs_with_cutpoints(spend: &Spend, max_size: usize) -> (V
     (ret_ser_tx, ret_cuts)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{convert_ledger_der_to_compact, csv_field, descriptor_checksum, split_command_line};
 
+    #[test]
+    fn ledger_der_truncated() {
+        let mut sig = vec![0x31, 0x44, 0x02, 0x20];
+        sig.extend(&[1; 32]);
+        sig.extend(&[0x02, 0x20]);
+        sig.extend(&[2; 32]);
 
+        let compact = convert_ledger_der_to_compact(&sig).unwrap();
+        assert_eq!(&compact[0..32], &[1; 32]);
+        assert_eq!(&compact[32..64], &[2; 32]);
+        // Every truncation must be rejected rather than panic
+        for len in 0..sig.len() {
+            assert!(convert_ledger_der_to_compact(&sig[..len]).is_err());
+        }
+    }
 
+    #[test]
+    fn ledger_der_bad_lengths() {
+        // r length pointing past the end, and s length pointing past the end
+        assert!(convert_ledger_der_to_compact(&[0x30, 0x44, 0x02, 0xff, 0, 0, 0, 0]).is_err());
+        assert!(convert_ledger_der_to_compact(&[0x30, 0x44, 0x02, 0x01, 0x01, 0x02, 0x20, 0x01]).is_err());
+    }
 
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("plain note"), "plain note");
+        assert_eq!(csv_field("alice, invoice 42"), "\"alice, invoice 42\"");
+        assert_eq!(csv_field("the \"big\" one"), "\"the \"\"big\"\" one\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn descriptor_checksums() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)"), Some("89f8spxm".to_owned()));  // from BIP 380
+        assert_eq!(descriptor_checksum("pkh([d34db33f/44'/0'/0']03a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd)"),
+                   Some("chffhyxs".to_owned()));
+        assert_eq!(descriptor_checksum("raw(deadbeef)\u{e9}"), None);
+    }
+
+    #[test]
+    fn command_line_splitting() {
+        let split = |line| split_command_line(line).unwrap();
+        assert_eq!(split("  signmessage 3  hello   world "), vec!["signmessage", "3", "hello", "world"]);
+        assert_eq!(split("signmessage 3 \"hello  world\""), vec!["signmessage", "3", "hello  world"]);
+        assert_eq!(split("queuepayment addr 1000 'rent for \"May\"'"), vec!["queuepayment", "addr", "1000", "rent for \"May\""]);
+        assert_eq!(split("a\\ b \"c\\\"d\\\\\" e''f \"\""), vec!["a b", "c\"d\\", "ef", ""]);
+        assert_eq!(split(""), Vec::<String>::new());
+        assert_eq!(split_command_line("note \"unterminated"), None);
+        assert_eq!(split_command_line("note 'unterminated"), None);
+        assert_eq!(split_command_line("trailing\\"), None);
+    }
+}
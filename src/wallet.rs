@@ -21,19 +21,30 @@ use bitcoin::{Address, Script, Transaction, TxOut, SigHashType};
 use bitcoin::blockdata::script;
 use bitcoin::network::constants::Network;
 use bitcoin::util::hash::Sha256dHash;
-use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BigEndian};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BigEndian, LittleEndian};
 use crypto::aes;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2;
 use hex::ToHex;
 use secp256k1::{self, Secp256k1};
-use std::{fmt, io, fs, str};
+use std::{cmp, fmt, io, fs, str};
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::io::{Read, Write};
+use std::path::Path;
 use time;
 
-use constants::wallet::{DECRYPTED_ENTRY_SIZE, ENCRYPTED_ENTRY_SIZE, MAGIC, MAGIC_TESTNET, MAX_USER_ID_BYTES, MAX_NOTE_BYTES, CHANGE_DUST};
+use constants::wallet::{DECRYPTED_ENTRY_SIZE, ENCRYPTED_ENTRY_SIZE, MAGIC, MAGIC_TESTNET, MAX_ENTRIES, MAX_USER_ID_BYTES, MAX_NOTE_BYTES, CHANGE_DUST, INPUT_SIZE_ESTIMATE};
+use constants::wallet::{MAGIC_FLAGS, CHECKSUM_SIZE, WALLET_BACKUPS_KEPT};
+use constants::wallet::header_flags;
+use constants::wallet::{BACKUP_MAGIC, BACKUP_SCRYPT_LOG_N, BACKUP_SCRYPT_R, BACKUP_SCRYPT_P};
 use dongle::Dongle;
 use error::Error;
-use util::{hash_sha256, convert_compact_to_secp};
+use util::{hash_sha256, hash160, convert_compact_to_secp, csv_field, descriptor_checksum, json_string, p2pkh_script};
 use spend;
 
 /// List of purposes that we use BIP32 keys
@@ -43,11 +54,18 @@ pub enum KeyPurpose {
     Address,
     /// The chaincode is an AES key
     AesKey,
+    /// A key used only to sign attestations about the wallet
+    Attestation,
+    /// The chaincode keys the payment queue
+    Queue,
     // TODO p2contract nonce (need Ledger support)
 }
 
-/// Obtain a BIP32 path corresponding to the appropriate key
+/// Obtain a BIP32 path corresponding to the appropriate key. Every component is
+/// hardened, so `account` and `index` must be less than 2^31.
 pub fn bip32_path(network: Network, account: u32, purpose: KeyPurpose, index: u32) -> [u32; 5] {
+    assert!(account < 0x80000000, "account {} is not a valid BIP32 hardened index", account);
+    assert!(index < 0x80000000, "index {} is not a valid BIP32 hardened index", index);
     let pp_index = match purpose {
         // 0 and 1 would correspond to "normal" or "change" in BIP44;
         // we deliberately avoid these indices to avoid standard BIP44
@@ -55,6 +73,8 @@ pub fn bip32_path(network: Network, account: u32, purpose: KeyPurpose, index: u3
         // hardened keys is already sufficient for this
         KeyPurpose::Address       => 2,
         KeyPurpose::AesKey        => 3,
+        KeyPurpose::Attestation   => 4,
+        KeyPurpose::Queue         => 5,
     };
     let coin_type = match network {
         Network::Bitcoin => 0x80000000,
@@ -64,26 +84,353 @@ pub fn bip32_path(network: Network, account: u32, purpose: KeyPurpose, index: u3
     [0x8000002c, coin_type, 0x80000000 | account, 0x80000000 | pp_index, 0x80000000 | index]
 }
 
+/// Formats a BIP32 path in the usual `m/44'/0'/...` notation
+pub fn bip32_path_string(path: &[u32]) -> String {
+    let mut ret = "m".to_owned();
+    for childnum in path {
+        if *childnum >= 0x80000000 {
+            ret.push_str(&format!("/{}'", childnum & 0x7fffffff));
+        } else {
+            ret.push_str(&format!("/{}", childnum));
+        }
+    }
+    ret
+}
+
+/// Queries the dongle for the fingerprint of its master key, the first four
+/// bytes of the hash160 of the compressed master public key
+pub fn master_fingerprint<D: Dongle>(dongle: &mut D) -> Result<[u8; 4], Error> {
+    let master = dongle.get_public_key(&[], false)?;
+    let mut ret = [0; 4];
+    ret.copy_from_slice(&hash160(&master.public_key.serialize()[..])[0..4]);
+    Ok(ret)
+}
+
 // This whole encryption business should be done on the dongle
+/// Helper function to compute the AES key for an entry. This is the chaincode
+/// of the entry's AES key path, HMAC'd with the passphrase key if the wallet
+/// has a passphrase.
+fn entry_key<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, layer: Option<&[u8; 32]>) -> Result<[u8; 32], Error> {
+    chaincode_key(dongle, &bip32_path(network, account, KeyPurpose::AesKey, index as u32), layer)
+}
+
+/// Helper function to compute the AES and HMAC keys for the payment queue.
+/// These are the HMAC-SHA512 of a fixed string keyed with the chaincode of
+/// the queue key path, itself HMAC'd with the passphrase key as in `entry_key`.
+fn queue_keys<D: Dongle>(dongle: &mut D, network: Network, account: u32, layer: Option<&[u8; 32]>) -> Result<([u8; 32], [u8; 32]), Error> {
+    let base = chaincode_key(dongle, &bip32_path(network, account, KeyPurpose::Queue, 0), layer)?;
+    let mut hmac = Hmac::new(sha2::Sha512::new(), &base[..]);
+    hmac.input(b"icebox payment queue");
+    let output = hmac.result();
+    let mut aes_key = [0; 32];
+    let mut mac_key = [0; 32];
+    aes_key.copy_from_slice(&output.code()[0..32]);
+    mac_key.copy_from_slice(&output.code()[32..64]);
+    Ok((aes_key, mac_key))
+}
+
+/// Helper function to get the chaincode at a path, HMAC'd with the passphrase
+/// key if there is one
+fn chaincode_key<D: Dongle>(dongle: &mut D, path: &[u32; 5], layer: Option<&[u8; 32]>) -> Result<[u8; 32], Error> {
+    let key = dongle.get_public_key(path, false)?;
+    let mut ret = [0; 32];
+    match layer {
+        Some(layer) => {
+            let mut hmac = Hmac::new(sha2::Sha256::new(), &layer[..]);
+            hmac.input(&key.chaincode[..]);
+            ret.copy_from_slice(hmac.result().code());
+        }
+        None => ret.copy_from_slice(&key.chaincode[..])
+    }
+    Ok(ret)
+}
+
 /// Helper function to encrypt an entry
-fn encrypt<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
-    let key = dongle.get_public_key(&bip32_path(network, account, KeyPurpose::AesKey, index as u32), false)?;
+fn encrypt<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, layer: Option<&[u8; 32]>, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+    let key = entry_key(dongle, network, account, index, layer)?;
     let iv = dongle.get_random(16)?;
-    let mut encryptor = aes::ctr(aes::KeySize::KeySize256, &key.chaincode[..], &iv);
+    let mut encryptor = aes::ctr(aes::KeySize::KeySize256, &key, &iv);
     output[0..16].copy_from_slice(&iv);
     encryptor.process(input, &mut output[16..]);
     Ok(())
 }
 
 /// Helper function to decrypt an entry
-fn decrypt<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
-    let key = dongle.get_public_key(&bip32_path(network, account, KeyPurpose::AesKey, index as u32), false)?;
+fn decrypt<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, layer: Option<&[u8; 32]>, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+    let key = entry_key(dongle, network, account, index, layer)?;
     let iv = &input[0..16];
-    let mut encryptor = aes::ctr(aes::KeySize::KeySize256, &key.chaincode[..], iv);
+    let mut encryptor = aes::ctr(aes::KeySize::KeySize256, &key, iv);
     encryptor.process(&input[16..], output);
     Ok(())
 }
 
+/// Derives the key which is layered into every entry key of a wallet with a
+/// passphrase
+fn passphrase_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let params = ScryptParams::new(BACKUP_SCRYPT_LOG_N, BACKUP_SCRYPT_R, BACKUP_SCRYPT_P);
+    let mut ret = [0; 32];
+    scrypt(passphrase.as_bytes(), salt, &params, &mut ret);
+    ret
+}
+
+/// Picks a fresh salt from the dongle's RNG and derives a passphrase key
+/// with it, returning both
+fn new_passphrase_layer<D: Dongle>(dongle: &mut D, passphrase: &str) -> Result<([u8; 16], [u8; 32]), Error> {
+    let random = dongle.get_random(16)?;
+    let mut salt = [0; 16];
+    salt.copy_from_slice(&random);
+    Ok((salt, passphrase_key(passphrase, &salt)))
+}
+
+/// Derives an AES key and an HMAC key for a backup file from a user passphrase
+fn backup_keys(passphrase: &str, salt: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let params = ScryptParams::new(BACKUP_SCRYPT_LOG_N, BACKUP_SCRYPT_R, BACKUP_SCRYPT_P);
+    let mut output = [0; 64];
+    scrypt(passphrase.as_bytes(), salt, &params, &mut output);
+    let mut aes_key = [0; 32];
+    let mut mac_key = [0; 32];
+    aes_key.copy_from_slice(&output[0..32]);
+    mac_key.copy_from_slice(&output[32..64]);
+    (aes_key, mac_key)
+}
+
+/// Computes a commitment to a set of outputs given as (txid, vout, amount),
+/// sorting them into commitment order.
+///
+/// The commitment is the SHA256 of the concatenation, for each output in
+/// order of serialized outpoint, of the 36-byte outpoint followed by the
+/// 8-byte little-endian amount; that is, the outputs serialized as in a
+/// Bitcoin transaction but with amounts in place of scriptpubkeys.
+pub fn commit_to_outputs(outputs: &mut Vec<(Sha256dHash, u32, u64)>) -> [u8; 32] {
+    let mut serialized: Vec<[u8; 44]> = outputs.iter().map(|&(ref txid, vout, amount)| {
+        let mut ser = [0; 44];
+        ser[0..32].copy_from_slice(&txid[..]);
+        LittleEndian::write_u32(&mut ser[32..36], vout);
+        LittleEndian::write_u64(&mut ser[36..44], amount);
+        ser
+    }).collect();
+    serialized.sort_by(|a, b| a[..].cmp(&b[..]));
+
+    let mut hasher = sha2::Sha256::new();
+    outputs.clear();
+    for ser in &serialized {
+        hasher.input(&ser[..]);
+        outputs.push((Sha256dHash::from(&ser[0..32]), LittleEndian::read_u32(&ser[32..36]), LittleEndian::read_u64(&ser[36..44])));
+    }
+    let mut result = [0; 32];
+    hasher.result(&mut result);
+    result
+}
+
+/// Given a p2pkh scriptpubkey, returns the other standard scriptpubkeys which
+/// pay to the same key. We cannot sign for these, but it is worth knowing if
+/// somebody sent coins to one of them.
+fn near_miss_scripts(p2pkh: &Script) -> Vec<(&'static str, Script)> {
+    let bytes = p2pkh.clone().into_vec();
+    if bytes.len() != 25 {
+        return vec![];
+    }
+    let mut p2wpkh = vec![0x00, 0x14];
+    p2wpkh.extend(&bytes[3..23]);
+    let mut p2sh_p2wpkh = vec![0xa9, 0x14];
+    p2sh_p2wpkh.extend(&hash160(&p2wpkh));
+    p2sh_p2wpkh.push(0x87);
+    vec![
+        ("p2wpkh", Script::from(p2wpkh)),
+        ("p2sh-wrapped p2wpkh", Script::from(p2sh_p2wpkh)),
+    ]
+}
+
+/// Checks that decrypted entry data is either blank or has a well-formed
+/// date. Data decrypted under the wrong key will be uniformly random and
+/// fail this check.
+fn is_plausible_entry(data: &[u8]) -> bool {
+    let date = &data[164..188];
+    if date.iter().all(|x| *x == 0) {
+        return true;
+    }
+    // YYYY-MM-DD HH:MM:SS+ZZZZ
+    date.iter().enumerate().all(|(i, ch)| match i {
+        4 | 7 => *ch == b'-',
+        10 => *ch == b' ',
+        13 | 16 => *ch == b':',
+        19 => *ch == b'+' || *ch == b'-',
+        _ => *ch >= b'0' && *ch <= b'9'
+    })
+}
+
+/// Parses an entry date `YYYY-MM-DD HH:MM:SS+ZZZZ` into seconds since the
+/// epoch. New entries are written in UTC, but older ones carry whatever local
+/// offset the writer had, so the offset is applied rather than assumed zero.
+fn parse_timestamp(date: &[u8]) -> Option<i64> {
+    let s = match str::from_utf8(date) {
+        Ok(s) if s.len() == 24 => s,
+        _ => return None
+    };
+    let tm = match time::strptime(&s[0..19], "%F %T") {
+        Ok(tm) => tm,
+        Err(_) => return None
+    };
+    let offset_mins = match (i64::from_str(&s[20..22]), i64::from_str(&s[22..24])) {
+        (Ok(h), Ok(m)) => h * 60 + m,
+        _ => return None
+    };
+    let offset_mins = if s.as_bytes()[19] == b'-' { -offset_mins } else { offset_mins };
+    Some(tm.to_timespec().sec - offset_mins * 60)
+}
+
+/// The version of this software, as recorded in the headers of wallet files
+/// that it writes
+fn software_version() -> (u16, u8, u8) {
+    (u16::from_str(env!("CARGO_PKG_VERSION_MAJOR")).unwrap(),
+     u8::from_str(env!("CARGO_PKG_VERSION_MINOR")).unwrap(),
+     u8::from_str(env!("CARGO_PKG_VERSION_PATCH")).unwrap())
+}
+
+/// The fields of a wallet file header, parsed without reference to a dongle
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Header {
+    network: Network,
+    account: u32,
+    writer_version: Option<(u16, u8, u8)>,
+    fingerprint: Option<[u8; 4]>,
+    salt: Option<[u8; 16]>,
+    checksummed: bool,
+    /// Size, in bytes, of the header as read
+    size: usize
+}
+
+impl Header {
+    /// Parses a wallet file header, failing on an unknown magic or if the
+    /// data ends before the header does
+    fn read<R: Read>(fh: &mut R) -> Result<Header, Error> {
+        let magic = fh.read_u64::<BigEndian>()?;
+        // The original format has no version or flags, and its magic gives
+        // the network
+        let legacy_flags = match magic {
+            MAGIC => Some(0),
+            MAGIC_TESTNET => Some(header_flags::TESTNET),
+            MAGIC_FLAGS => None,
+            _ => return Err(Error::WalletWrongMagic(magic))
+        };
+
+        let account = fh.read_u32::<BigEndian>()?;
+        if account >= 0x80000000 {
+            return Err(Error::AccountOutOfRange(account));
+        }
+        let mut size = 12;
+        let (writer_version, flags) = match legacy_flags {
+            Some(flags) => (None, flags),
+            None => {
+                let major = fh.read_u16::<BigEndian>()?;
+                let minor = fh.read_u8()?;
+                let patch = fh.read_u8()?;
+                let flags = fh.read_u32::<BigEndian>()?;
+                if flags & !header_flags::ALL != 0 {
+                    return Err(Error::WalletUnknownFlags(flags));
+                }
+                size += 8;
+                (Some((major, minor, patch)), flags)
+            }
+        };
+
+        let fingerprint = if flags & header_flags::FINGERPRINT != 0 {
+            let mut fingerprint = [0; 4];
+            fh.read_exact(&mut fingerprint)?;
+            size += 4;
+            Some(fingerprint)
+        } else {
+            None
+        };
+        let salt = if flags & header_flags::PASSPHRASE != 0 {
+            let mut salt = [0; 16];
+            fh.read_exact(&mut salt)?;
+            size += 16;
+            Some(salt)
+        } else {
+            None
+        };
+
+        Ok(Header {
+            network: if flags & header_flags::TESTNET != 0 { Network::Testnet } else { Network::Bitcoin },
+            account: account,
+            writer_version: writer_version,
+            fingerprint: fingerprint,
+            salt: salt,
+            checksummed: flags & header_flags::CHECKSUM != 0,
+            size: size
+        })
+    }
+}
+
+/// Checks the size and header of a backup file written by `backup`, without
+/// checking its MAC, returning its network, account and number of entries
+fn read_backup_header(data: &[u8]) -> Result<(Network, u32, usize), Error> {
+    if data.len() < 56 + 32 {
+        return Err(Error::BackupWrongSize(data.len()));
+    }
+
+    let magic = BigEndian::read_u64(&data[0..8]);
+    if magic != BACKUP_MAGIC {
+        return Err(Error::BackupWrongMagic(magic));
+    }
+    let network = match BigEndian::read_u64(&data[8..16]) {
+        MAGIC => Network::Bitcoin,
+        MAGIC_TESTNET => Network::Testnet,
+        wallet_magic => { return Err(Error::WalletWrongMagic(wallet_magic)); }
+    };
+    let account = BigEndian::read_u32(&data[16..20]);
+    if account >= 0x80000000 {
+        return Err(Error::AccountOutOfRange(account));
+    }
+    let n_entries = BigEndian::read_u32(&data[20..24]) as usize;
+    if n_entries > MAX_ENTRIES {
+        return Err(Error::TooManyEntries(n_entries, MAX_ENTRIES));
+    }
+    // Compare entry counts rather than sizes, so a huge count cannot overflow
+    if (data.len() - 56 - 32) % DECRYPTED_ENTRY_SIZE != 0 || (data.len() - 56 - 32) / DECRYPTED_ENTRY_SIZE != n_entries {
+        return Err(Error::BackupWrongSize(data.len()));
+    }
+    Ok((network, account, n_entries))
+}
+
+/// Splits the contents of a wallet file following its header into entries,
+/// checking the file's size and, if it has one, its checksum
+fn read_entries(data: &[u8], header_size: usize, checksummed: bool) -> Result<Vec<[u8; ENCRYPTED_ENTRY_SIZE]>, Error> {
+    let size = data.len();
+    let footer_size = if checksummed { CHECKSUM_SIZE } else { 0 };
+    if size < header_size + footer_size || (size - header_size - footer_size) % ENCRYPTED_ENTRY_SIZE != 0 {
+        return Err(Error::WalletWrongSize(size));
+    }
+    if checksummed {
+        let (body, checksum) = data.split_at(size - CHECKSUM_SIZE);
+        if hash_sha256(body)[..] != checksum[..] {
+            return Err(Error::WalletBadChecksum);
+        }
+    }
+    let n_entries = (size - header_size - footer_size) / ENCRYPTED_ENTRY_SIZE;
+    if n_entries > MAX_ENTRIES {
+        return Err(Error::TooManyEntries(n_entries, MAX_ENTRIES));
+    }
+
+    let mut entries = Vec::with_capacity(n_entries);
+    for chunk in data[header_size..size - footer_size].chunks(ENCRYPTED_ENTRY_SIZE) {
+        let mut entry = [0; ENCRYPTED_ENTRY_SIZE];
+        entry.copy_from_slice(chunk);
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// (Over)estimates the size of a transaction paying to `outputs` and to a
+/// p2pkh change output, before any inputs are added
+fn base_size_estimate(outputs: &[TxOut]) -> u64 {
+    // Version, locktime and counts, then per output an 8-byte value and a
+    // script with its length prefix, and 34 bytes of p2pkh change
+    let outputs_size: usize = outputs.iter().map(|output| output.script_pubkey.len() + 9).sum();
+    (13 + outputs_size + 34) as u64
+}
+
 /// Extra information needed when updating an entry
 pub enum Update<'a> {
     /// This entry should be labelled etc but has not yet received any coins
@@ -92,21 +439,72 @@ pub enum Update<'a> {
     Change(&'a Transaction, u32)
 }
 
+/// Policy for choosing which unspent entries fund a spend
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CoinSelection {
+    /// Take unspent entries in wallet order until the spend is funded
+    InOrder,
+    /// Take every unspent entry worth less than `below` which is worth more
+    /// than the fee to spend it, up to `max_inputs` of them
+    Consolidate {
+        /// Only entries worth less than this many satoshi are taken
+        below: u64,
+        /// Maximum number of entries to take
+        max_inputs: usize,
+        /// Indices of entries never to take, e.g. the unconfirmed change of
+        /// an earlier consolidation
+        exclude: Vec<usize>
+    },
+    /// Take exactly the given outpoints, failing if any is not an unspent
+    /// entry or if together they do not fund the spend
+    Explicit(Vec<(Sha256dHash, u32)>)
+}
+
 /// Structure representing an encrypted wallet
 pub struct EncryptedWallet {
     network: Network,
     account: u32,
+    /// Version of the software which last wrote the wallet file, if recorded
+    writer_version: Option<(u16, u8, u8)>,
+    /// Master key fingerprint of the dongle the wallet belongs to
+    fingerprint: [u8; 4],
+    /// Whether the file the wallet was loaded from ended in a checksum
+    checksummed: bool,
+    /// Salt and derived key, if entry keys are layered with a passphrase
+    passphrase: Option<([u8; 16], [u8; 32])>,
+    /// Whether the passphrase has changed since the file was last saved, so
+    /// that older copies of it are readable with a different passphrase
+    passphrase_changed: Cell<bool>,
+    /// Whether previous versions of the file have already been rotated by
+    /// this instance, which is done only on the first save
+    rotated: Cell<bool>,
     entries: Vec<[u8; ENCRYPTED_ENTRY_SIZE]>
 }
 
 impl EncryptedWallet {
-    /// Construct a new empty wallet with the given account number
-    pub fn new<D: Dongle>(dongle: &mut D, network: Network, account: u32, n_entries: usize) -> Result<EncryptedWallet, Error> {
+    /// Construct a new empty wallet with the given account number, with entry
+    /// keys layered with a passphrase if one is given
+    pub fn new<D: Dongle>(dongle: &mut D, network: Network, account: u32, n_entries: usize, passphrase: Option<&str>) -> Result<EncryptedWallet, Error> {
+        if account >= 0x80000000 {
+            return Err(Error::AccountOutOfRange(account));
+        }
+        if n_entries > MAX_ENTRIES {
+            return Err(Error::TooManyEntries(n_entries, MAX_ENTRIES));
+        }
         let mut ret = EncryptedWallet {
             network: network,
             account: account,
+            writer_version: None,
+            fingerprint: master_fingerprint(dongle)?,
+            checksummed: false,
+            passphrase: None,
+            passphrase_changed: Cell::new(false),
+            rotated: Cell::new(false),
             entries: Vec::with_capacity(n_entries)
         };
+        if let Some(passphrase) = passphrase {
+            ret.passphrase = Some(new_passphrase_layer(dongle, passphrase)?);
+        }
 
         dongle.set_network(network)?;
 
@@ -114,7 +512,7 @@ impl EncryptedWallet {
             info!("Encrypting zeroes for key {}", i);
             let mut block = [0; ENCRYPTED_ENTRY_SIZE];
             let zeroes = [0; DECRYPTED_ENTRY_SIZE];
-            encrypt(dongle, network, account, ret.entries.len(), &zeroes, &mut block)?;
+            encrypt(dongle, network, account, ret.entries.len(), ret.layer(), &zeroes, &mut block)?;
             ret.entries.push(block);
         }
 
@@ -126,69 +524,407 @@ impl EncryptedWallet {
         if n_entries <= self.entries.len() {
             return Ok(());
         }
+        if n_entries > MAX_ENTRIES {
+            return Err(Error::TooManyEntries(n_entries, MAX_ENTRIES));
+        }
         for i in self.entries.len()..n_entries {
             info!("Encrypting zeroes for key {}", i);
             let mut block = [0; ENCRYPTED_ENTRY_SIZE];
             let zeroes = [0; DECRYPTED_ENTRY_SIZE];
-            encrypt(dongle, self.network, self.account, i, &zeroes, &mut block)?;
+            encrypt(dongle, self.network, self.account, i, self.layer(), &zeroes, &mut block)?;
             self.entries.push(block);
         }
         Ok(())
     }
 
-    /// Saves out the wallet to a file
+    /// Saves out the wallet to a file. Refuses to overwrite a wallet that was
+    /// last written by a newer major version, since it may contain data that
+    /// this version does not know to preserve.
+    ///
+    /// The file header is
+    /// +------------+-----------------------------------------+-----------+
+    /// | Field      | Description                             | Size      |
+    /// +------------+-----------------------------------------+-----------+
+    /// | Magic      | `MAGIC_FLAGS`, big endian               |   8 bytes |
+    /// | Account    | Account number, big endian              |   4 bytes |
+    /// | Version    | Writer major (2 bytes), minor, patch    |   4 bytes |
+    /// | Flags      | `header_flags`, big endian              |   4 bytes |
+    /// | Fingerprint| Master key fingerprint of the dongle    |   4 bytes |
+    /// | Salt       | Passphrase salt (`PASSPHRASE` flag only)|  16 bytes |
+    /// +------------+-----------------------------------------+-----------+
+    ///
+    /// The entries follow, and then a `CHECKSUM_SIZE`-byte SHA256 of all the
+    /// preceding bytes of the file. Files are always written with the
+    /// `FINGERPRINT` and `CHECKSUM` flags.
+    ///
+    /// Files with the original `MAGIC` or `MAGIC_TESTNET` are still read.
+    /// They have only the magic and account fields, no checksum, and the
+    /// magic gives the network.
+    ///
+    /// The new file is written and synced to disk under a temporary name,
+    /// then renamed over the old one. On the first save of a loaded wallet
+    /// the previous versions are rotated; on the first save after a change
+    /// of passphrase they are deleted instead, since they can be read with
+    /// the old passphrase.
     pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let (major, minor, patch) = software_version();
+        if let Some((w_major, w_minor, w_patch)) = self.writer_version {
+            if w_major > major {
+                return Err(Error::WalletTooNew(w_major, w_minor, w_patch));
+            }
+        }
+
         let mut temp_name = filename.to_owned();
         temp_name.push_str(".0");
         let fh = fs::File::create(&temp_name)?;
         let mut buf = io::BufWriter::new(fh);
+        let mut flags = header_flags::FINGERPRINT | header_flags::CHECKSUM;
         if self.network == Network::Testnet {
-            buf.write_u64::<BigEndian>(MAGIC_TESTNET)?;
-        } else {
-            buf.write_u64::<BigEndian>(MAGIC)?;
+            flags |= header_flags::TESTNET;
         }
-        buf.write_u32::<BigEndian>(self.account)?;
+        if self.passphrase.is_some() {
+            flags |= header_flags::PASSPHRASE;
+        }
+        let mut header = vec![];
+        header.write_u64::<BigEndian>(MAGIC_FLAGS)?;
+        header.write_u32::<BigEndian>(self.account)?;
+        header.write_u16::<BigEndian>(major)?;
+        header.write_u8(minor)?;
+        header.write_u8(patch)?;
+        header.write_u32::<BigEndian>(flags)?;
+        header.write_all(&self.fingerprint[..])?;
+        if let Some((ref salt, _)) = self.passphrase {
+            header.write_all(&salt[..])?;
+        }
+        let mut hasher = sha2::Sha256::new();
+        hasher.input(&header);
+        buf.write_all(&header)?;
         for data in &self.entries {
-            buf.write(&data[..])?;
+            hasher.input(&data[..]);
+            buf.write_all(&data[..])?;
+        }
+        let mut checksum = [0; CHECKSUM_SIZE];
+        hasher.result(&mut checksum);
+        buf.write_all(&checksum)?;
+        buf.flush()?;
+        buf.get_ref().sync_all()?;
+
+        if self.passphrase_changed.get() {
+            let mut removed = vec![];
+            for i in 1..WALLET_BACKUPS_KEPT + 1 {
+                let name = format!("{}.{}", filename, i);
+                if fs::metadata(&name).is_ok() {
+                    fs::remove_file(&name)?;
+                    removed.push(name);
+                }
+            }
+            if !removed.is_empty() {
+                warn!("The passphrase changed, so previous versions of the wallet were deleted: {}", removed.join(", "));
+                warn!("They were unlinked, not overwritten, and may still be recoverable from the disk.");
+            }
+            self.passphrase_changed.set(false);
+            self.rotated.set(true);
+        } else if !self.rotated.get() {
+            // Keep the last few versions, so that a bad save never destroys
+            // the only copy. Copy rather than rename the current file so that
+            // there is always a wallet at `filename`.
+            if fs::metadata(filename).is_ok() {
+                for i in (1..WALLET_BACKUPS_KEPT).rev() {
+                    let from = format!("{}.{}", filename, i);
+                    if fs::metadata(&from).is_ok() {
+                        fs::rename(&from, format!("{}.{}", filename, i + 1))?;
+                    }
+                }
+                fs::copy(filename, format!("{}.1", filename))?;
+            }
+            self.rotated.set(true);
         }
         fs::rename(&temp_name, filename)?;
+        // Sync the directory too so the rename itself is durable. Not every
+        // platform can open a directory, so this is best-effort.
+        let dir = match Path::new(filename).parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new(".")
+        };
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
         info!("Saved wallet to {}", filename);
         Ok(())
     }
 
-    /// Loads a wallet from a file
-    pub fn load<D: Dongle>(dongle: &mut D, filename: &str) -> Result<EncryptedWallet, Error> {
-        let meta = fs::metadata(filename)?;
-        let size = meta.len() as usize;
+    /// Writes the decrypted wallet contents to a backup file encrypted under a
+    /// passphrase rather than under dongle-derived keys
+    ///
+    /// The backup format is
+    /// +------------+-----------------------------------------+-----------+
+    /// | Field      | Description                             | Size      |
+    /// +------------+-----------------------------------------+-----------+
+    /// | Magic      | `BACKUP_MAGIC`, big endian              |   8 bytes |
+    /// | Wallet     | Wallet magic (gives network), big endian|   8 bytes |
+    /// | Account    | Account number, big endian              |   4 bytes |
+    /// | Entries    | Number of entries, big endian           |   4 bytes |
+    /// | Salt       | scrypt salt                             |  16 bytes |
+    /// | IV         | AES-CTR IV                              |  16 bytes |
+    /// | Data       | AES-CTR-encrypted decrypted entries     | 336 each  |
+    /// | MAC        | HMAC-SHA256 of all preceding data       |  32 bytes |
+    /// +------------+-----------------------------------------+-----------+
+    ///
+    pub fn backup<D: Dongle>(&self, dongle: &mut D, passphrase: &str, filename: &str) -> Result<(), Error> {
+        let random = dongle.get_random(32)?;
+        let (salt, iv) = random.split_at(16);
+        let (aes_key, mac_key) = backup_keys(passphrase, salt);
 
-        if size % ENCRYPTED_ENTRY_SIZE != 12 {
-            return Err(Error::WalletWrongSize(size));
+        let mut data = Vec::with_capacity(56 + self.entries.len() * DECRYPTED_ENTRY_SIZE + 32);
+        data.write_u64::<BigEndian>(BACKUP_MAGIC)?;
+        data.write_u64::<BigEndian>(self.magic())?;
+        data.write_u32::<BigEndian>(self.account)?;
+        data.write_u32::<BigEndian>(self.entries.len() as u32)?;
+        data.extend(salt);
+        data.extend(iv);
+        let mut encryptor = aes::ctr(aes::KeySize::KeySize256, &aes_key, iv);
+        for i in 0..self.entries.len() {
+            let mut tmp = [0; DECRYPTED_ENTRY_SIZE];
+            let mut block = [0; DECRYPTED_ENTRY_SIZE];
+            decrypt(dongle, self.network, self.account, i, self.layer(), &self.entries[i], &mut tmp)?;
+            encryptor.process(&tmp, &mut block);
+            data.extend(&block[..]);
         }
+        let mut hmac = Hmac::new(sha2::Sha256::new(), &mac_key);
+        hmac.input(&data);
+        data.extend(hmac.result().code());
+
+        let mut fh = fs::File::create(filename)?;
+        fh.write_all(&data)?;
+        info!("Wrote backup of {} entries to {}", self.entries.len(), filename);
+        Ok(())
+    }
+
+    /// Encrypts the text of the payment queue, which is kept beside the wallet
+    /// file, under keys derived from the dongle (see `queue_keys`)
+    ///
+    /// The result is a 16-byte IV, the text encrypted with AES-256-CTR, and
+    /// an HMAC-SHA256 of both.
+    pub fn encrypt_queue<D: Dongle>(&self, dongle: &mut D, text: &[u8]) -> Result<Vec<u8>, Error> {
+        let (aes_key, mac_key) = queue_keys(dongle, self.network, self.account, self.layer())?;
+        let iv = dongle.get_random(16)?;
+        let mut data = vec![0; 16 + text.len()];
+        data[0..16].copy_from_slice(&iv);
+        let mut encryptor = aes::ctr(aes::KeySize::KeySize256, &aes_key, &iv);
+        encryptor.process(text, &mut data[16..]);
+        let mut hmac = Hmac::new(sha2::Sha256::new(), &mac_key);
+        hmac.input(&data);
+        data.extend(hmac.result().code());
+        Ok(data)
+    }
+
+    /// Authenticates and decrypts payment queue data written by `encrypt_queue`
+    pub fn decrypt_queue<D: Dongle>(&self, dongle: &mut D, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < 16 + 32 {
+            return Err(Error::QueueBadMac);
+        }
+        let (aes_key, mac_key) = queue_keys(dongle, self.network, self.account, self.layer())?;
+        let (body, mac) = data.split_at(data.len() - 32);
+        let mut hmac = Hmac::new(sha2::Sha256::new(), &mac_key);
+        hmac.input(body);
+        if hmac.result() != MacResult::new(mac) {
+            return Err(Error::QueueBadMac);
+        }
+        let mut text = vec![0; body.len() - 16];
+        let mut decryptor = aes::ctr(aes::KeySize::KeySize256, &aes_key, &body[0..16]);
+        decryptor.process(&body[16..], &mut text);
+        Ok(text)
+    }
+
+    /// Reads a backup file written by `backup` and re-encrypts its contents
+    /// under the keys of the connected dongle. If the dongle does not have
+    /// the same seed as the one which made the backup, the notes are still
+    /// recovered but every used entry will fail signature verification.
+    pub fn restore<D: Dongle>(dongle: &mut D, passphrase: &str, filename: &str) -> Result<EncryptedWallet, Error> {
+        let mut data = vec![];
+        fs::File::open(filename)?.read_to_end(&mut data)?;
+        let (network, account, n_entries) = read_backup_header(&data)?;
+
+        let (body, mac) = data.split_at(data.len() - 32);
+        let (aes_key, mac_key) = backup_keys(passphrase, &body[24..40]);
+        let mut hmac = Hmac::new(sha2::Sha256::new(), &mac_key);
+        hmac.input(body);
+        if hmac.result() != MacResult::new(mac) {
+            return Err(Error::BackupBadMac);
+        }
+
+        dongle.set_network(network)?;
 
         let mut ret = EncryptedWallet {
-            network: Network::Bitcoin,
-            account: 0,
-            entries: Vec::with_capacity(size / ENCRYPTED_ENTRY_SIZE)
+            network: network,
+            account: account,
+            writer_version: None,
+            fingerprint: master_fingerprint(dongle)?,
+            checksummed: false,
+            passphrase: None,
+            passphrase_changed: Cell::new(false),
+            rotated: Cell::new(false),
+            entries: Vec::with_capacity(n_entries)
         };
+        let mut decryptor = aes::ctr(aes::KeySize::KeySize256, &aes_key, &body[40..56]);
+        for i in 0..n_entries {
+            info!("Re-encrypting entry {}", i);
+            let mut tmp = [0; DECRYPTED_ENTRY_SIZE];
+            let mut block = [0; ENCRYPTED_ENTRY_SIZE];
+            decryptor.process(&body[56 + i * DECRYPTED_ENTRY_SIZE..56 + (i + 1) * DECRYPTED_ENTRY_SIZE], &mut tmp);
+            encrypt(dongle, network, account, i, None, &tmp, &mut block)?;
+            ret.entries.push(block);
+        }
+        Ok(ret)
+    }
 
-        let mut fh = fs::File::open(filename)?;
-        let magic = fh.read_u64::<BigEndian>()?;
-        match magic {
-            MAGIC => {},
-            MAGIC_TESTNET => { ret.network = Network::Testnet; }
-            _ => { return Err(Error::WalletWrongMagic(magic)); }
+    /// Loads a wallet without a passphrase from a file
+    pub fn load<D: Dongle>(dongle: &mut D, filename: &str) -> Result<EncryptedWallet, Error> {
+        EncryptedWallet::load_with_passphrase(dongle, filename, None)
+    }
+
+    /// Loads a wallet from a file, using `passphrase` if the wallet has one
+    pub fn load_with_passphrase<D: Dongle>(dongle: &mut D, filename: &str, passphrase: Option<&str>) -> Result<EncryptedWallet, Error> {
+        let mut data = vec![];
+        fs::File::open(filename)?.read_to_end(&mut data)?;
+        let (mut ret, header_size) = EncryptedWallet::read_header(dongle, &mut &data[..], passphrase)?;
+        ret.entries = read_entries(&data, header_size, ret.checksummed)?;
+
+        dongle.set_network(ret.network)?;
+
+        // With the wrong dongle every entry decrypts to garbage. Check the first
+        // few so we can say so, rather than failing confusingly later on.
+        let n_check = cmp::min(ret.entries.len(), 3);
+        let mut n_garbage = 0;
+        for i in 0..n_check {
+            let mut tmp = [0; DECRYPTED_ENTRY_SIZE];
+            decrypt(dongle, ret.network, ret.account, i, ret.layer(), &ret.entries[i], &mut tmp)?;
+            if !is_plausible_entry(&tmp) {
+                n_garbage += 1;
+            }
+        }
+        if n_check > 0 && n_garbage == n_check {
+            if ret.passphrase.is_some() {
+                return Err(Error::WrongPassphrase);
+            }
+            return Err(Error::WrongDongle);
         }
 
-        ret.account = fh.read_u32::<BigEndian>()?;
-        for _ in 0..ret.entries.capacity() {
+        Ok(ret)
+    }
+
+    /// Reads as much as possible of a damaged wallet file. The header must be
+    /// intact; after it, any trailing partial entry is dropped, and entries
+    /// which do not decrypt to valid data are replaced by fresh entries marked
+    /// as issued, so that their addresses are never handed out again. Entries
+    /// which decrypt but whose signatures do not verify are kept as they are,
+    /// since their contents may still be worth examining. Returns the wallet,
+    /// the replaced indices and the kept indices with bad signatures.
+    pub fn recover<D: Dongle>(dongle: &mut D, filename: &str, passphrase: Option<&str>) -> Result<(EncryptedWallet, Vec<usize>, Vec<usize>), Error> {
+        let mut file = vec![];
+        fs::File::open(filename)?.read_to_end(&mut file)?;
+        let (mut ret, header_size) = EncryptedWallet::read_header(dongle, &mut &file[..], passphrase)?;
+
+        // A checksum is only recognizable if the file ends on one; otherwise
+        // assume it was lost along with the end of the file
+        let mut data = &file[header_size..];
+        if ret.checksummed && data.len() % ENCRYPTED_ENTRY_SIZE == CHECKSUM_SIZE {
+            let (body, checksum) = file.split_at(file.len() - CHECKSUM_SIZE);
+            if hash_sha256(body)[..] != checksum[..] {
+                warn!("Wallet checksum does not match; checking every entry.");
+            }
+            data = &data[..data.len() - CHECKSUM_SIZE];
+        } else if ret.checksummed {
+            warn!("Wallet checksum is missing (file truncated?); checking every entry.");
+        }
+
+        let n_entries = cmp::min(data.len() / ENCRYPTED_ENTRY_SIZE, MAX_ENTRIES);
+        if data.len() != n_entries * ENCRYPTED_ENTRY_SIZE {
+            warn!("Dropping {} bytes after the last whole entry.", data.len() - n_entries * ENCRYPTED_ENTRY_SIZE);
+        }
+        for chunk in data.chunks(ENCRYPTED_ENTRY_SIZE).take(n_entries) {
             let mut entry = [0; ENCRYPTED_ENTRY_SIZE];
-            fh.read_exact(&mut entry)?;
+            entry.copy_from_slice(chunk);
             ret.entries.push(entry);
         }
 
         dongle.set_network(ret.network)?;
 
-        Ok(ret)
+        let mut bad = vec![];
+        let mut bad_signature = vec![];
+        for i in 0..ret.entries.len() {
+            match ret.lookup(dongle, i) {
+                Ok(ref entry) if entry.state == EntryState::Invalid => {
+                    warn!("Entry {} has a bad signature; keeping it as it is. Its contents are:\n{}", i, entry);
+                    bad_signature.push(i);
+                }
+                Ok(_) => {}
+                Err(Error::EntryCorrupted(_)) | Err(Error::Utf8(_)) => {
+                    warn!("Entry {} did not decrypt to valid data.", i);
+                    bad.push(i);
+                }
+                Err(e) => return Err(e)
+            }
+        }
+        // If nothing at all decrypts this is the wrong dongle or passphrase,
+        // and "repairing" would throw the whole wallet away
+        if !ret.entries.is_empty() && bad.len() == ret.entries.len() {
+            if ret.passphrase.is_some() {
+                return Err(Error::WrongPassphrase);
+            }
+            return Err(Error::WrongDongle);
+        }
+
+        for &i in &bad {
+            info!("Replacing entry {}", i);
+            ret.update(dongle, i, "recover".to_owned(), Sha256dHash::from(&[0u8; 32][..]),
+                       Update::Unused("entry was unreadable when the wallet was recovered".to_owned()))?;
+        }
+        Ok((ret, bad, bad_signature))
+    }
+
+    /// Reads a wallet file header, checking the dongle's fingerprint against
+    /// it if it has one, and returns a wallet with no entries along with the
+    /// size of the header
+    fn read_header<D: Dongle, R: Read>(dongle: &mut D, fh: &mut R, passphrase: Option<&str>) -> Result<(EncryptedWallet, usize), Error> {
+        let header = Header::read(fh)?;
+        let mut ret = EncryptedWallet {
+            network: header.network,
+            account: header.account,
+            writer_version: header.writer_version,
+            fingerprint: [0; 4],
+            checksummed: header.checksummed,
+            passphrase: None,
+            passphrase_changed: Cell::new(false),
+            rotated: Cell::new(false),
+            entries: vec![]
+        };
+
+        if let Some((major, minor, patch)) = header.writer_version {
+            if major > software_version().0 {
+                warn!("Wallet was last written by version {}.{}.{}, which is newer than this one ({}).",
+                      major, minor, patch, env!("CARGO_PKG_VERSION"));
+                warn!("It may contain data this version does not understand, so it will not be saved.");
+            }
+        }
+        // Check the dongle before deriving anything from it, so that the wrong
+        // device is reported as such rather than as a corrupted wallet
+        let dongle_fingerprint = master_fingerprint(dongle)?;
+        match header.fingerprint {
+            Some(fingerprint) => if fingerprint != dongle_fingerprint {
+                return Err(Error::WrongDevice(fingerprint, dongle_fingerprint));
+            },
+            // Older wallets get the fingerprint recorded on their next save
+            None => {}
+        }
+        ret.fingerprint = dongle_fingerprint;
+        match (header.salt, passphrase) {
+            (Some(salt), Some(passphrase)) => ret.passphrase = Some((salt, passphrase_key(passphrase, &salt))),
+            (Some(_), None) => return Err(Error::PassphraseRequired),
+            (None, Some(_)) => warn!("Wallet has no passphrase; ignoring the one given."),
+            (None, None) => {}
+        }
+        Ok((ret, header.size))
     }
 
     /// Scan the wallet for the first unused index
@@ -208,7 +944,7 @@ impl EncryptedWallet {
             return Err(Error::EntryOutOfRange(index));
         }
 
-        Entry::decrypt_and_verify(dongle, self.network, self.account, index, &self.entries[index])
+        Entry::decrypt_and_verify(dongle, self.network, self.account, index, self.layer(), &self.entries[index])
     }
 
     /// Does a linear scan for a base58-encoded address
@@ -216,7 +952,7 @@ impl EncryptedWallet {
         for (i, entry) in self.entries.iter().enumerate() {
             let key = dongle.get_public_key(&bip32_path(self.network, self.account, KeyPurpose::Address, i as u32), false)?;
             if key.b58_address == address {
-                return Entry::decrypt_and_verify(dongle, self.network, self.account, i, entry);
+                return Entry::decrypt_and_verify(dongle, self.network, self.account, i, self.layer(), entry);
             }
         }
         Err(Error::AddressNotFound)
@@ -235,7 +971,7 @@ impl EncryptedWallet {
             return Err(Error::UserIdTooLong(user.as_bytes().len(), MAX_USER_ID_BYTES));
         }
 
-        let timestr = time::strftime("%F %T%z", &time::now()).unwrap();
+        let timestr = time::strftime("%F %T%z", &time::now_utc()).unwrap();
         assert_eq!(timestr.bytes().len(), 24);
         let mut timesl = [0; 24];
         timesl.clone_from_slice(timestr.as_bytes());
@@ -288,7 +1024,7 @@ impl EncryptedWallet {
             note: note
         };
 
-        self.entries[index] = entry.sign_and_encrypt(dongle, self.network, self.account, index)?;
+        self.entries[index] = entry.sign_and_encrypt(dongle, self.network, self.account, index, self.layer())?;
 
         Ok(entry)
     }
@@ -306,9 +1042,11 @@ impl EncryptedWallet {
     }
 
     /// Process a transaction which claims to send coins to this wallet,
-    /// finding all output which send coins to us
-    pub fn receive<D: Dongle>(&mut self, dongle: &mut D, tx: &Transaction) -> Result<(), Error> {
+    /// finding all output which send coins to us. Returns the indices of
+    /// the entries which were updated.
+    pub fn receive<D: Dongle>(&mut self, dongle: &mut D, tx: &Transaction) -> Result<Vec<usize>, Error> {
         let txid = tx.txid();
+        let mut updated = vec![];
 
         for i in 0..self.entries.len() {
             let mut entry = self.lookup(dongle, i)?;
@@ -319,7 +1057,14 @@ impl EncryptedWallet {
                 continue;
             }
             let spk = entry.address.script_pubkey();
+            let near_misses = near_miss_scripts(&spk);
             for (vout, out) in tx.output.iter().enumerate() {
+                for &(kind, ref script) in &near_misses {
+                    if out.script_pubkey == *script {
+                        warn!("Output {}:{} pays {} to the key of entry {} as {}, not p2pkh.", txid, vout, out.value, i, kind);
+                        warn!("This wallet will NOT record or be able to spend these coins.");
+                    }
+                }
                 if out.script_pubkey == spk {
                     info!("Receive to entry {}. Amount {}, outpoint {}:{}!", i, out.value, txid, vout);
                     // Before updating anything check the state of the entry to see if this is allowed.
@@ -347,20 +1092,21 @@ impl EncryptedWallet {
                             entry.txid.copy_from_slice(&txid[..]);
                             entry.vout = vout as u32;
                             entry.amount = out.value;
-                            self.entries[i] = entry.sign_and_encrypt(dongle, self.network, self.account, i)?;
+                            self.entries[i] = entry.sign_and_encrypt(dongle, self.network, self.account, i, self.layer())?;
+                            updated.push(i);
                         }
                     }
                 }
             } // end txo loop
         } // end entries loop
-        Ok(())
+        Ok(updated)
     }
 
     /// Mark an address as having been spent
     pub fn mark_spent<D: Dongle>(&mut self, dongle: &mut D, index: usize) -> Result<(), Error> {
         let mut entry = self.lookup(dongle, index)?;
         entry.spent = true;
-        self.entries[index] = entry.sign_and_encrypt(dongle, self.network, self.account, index)?;
+        self.entries[index] = entry.sign_and_encrypt(dongle, self.network, self.account, index, self.layer())?;
         Ok(())
     }
 
@@ -370,20 +1116,71 @@ impl EncryptedWallet {
     pub fn rerandomize<D: Dongle>(&mut self, dongle: &mut D) -> Result<(), Error> {
         for i in 0..self.entries.len() {
             let mut tmp = [0; DECRYPTED_ENTRY_SIZE];
-            decrypt(dongle, self.network, self.account, i, &self.entries[i], &mut tmp)?;
-            encrypt(dongle, self.network, self.account, i, &tmp, &mut self.entries[i])?;
+            decrypt(dongle, self.network, self.account, i, self.layer(), &self.entries[i], &mut tmp)?;
+            encrypt(dongle, self.network, self.account, i, self.layer(), &tmp, &mut self.entries[i])?;
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts the entire wallet with entry keys layered with a new
+    /// passphrase, or with no passphrase if `None` is given. Until the wallet
+    /// is saved, the file on disk still uses the old passphrase.
+    pub fn set_passphrase<D: Dongle>(&mut self, dongle: &mut D, passphrase: Option<&str>) -> Result<(), Error> {
+        let new_passphrase = match passphrase {
+            Some(passphrase) => Some(new_passphrase_layer(dongle, passphrase)?),
+            None => None
+        };
+        for i in 0..self.entries.len() {
+            info!("Re-encrypting entry {}", i);
+            let mut tmp = [0; DECRYPTED_ENTRY_SIZE];
+            decrypt(dongle, self.network, self.account, i, self.layer(), &self.entries[i], &mut tmp)?;
+            let layer = new_passphrase.as_ref().map(|&(_, ref key)| key);
+            encrypt(dongle, self.network, self.account, i, layer, &tmp, &mut self.entries[i])?;
         }
+        self.passphrase = new_passphrase;
+        self.passphrase_changed.set(true);
         Ok(())
     }
 
+    /// Does a linear scan to compute a commitment to the wallet's unspent
+    /// outputs (see `commit_to_outputs`), returning the outputs as (txid,
+    /// vout, amount) in commitment order along with the commitment
+    pub fn utxo_commitment<D: Dongle>(&self, dongle: &mut D) -> Result<(Vec<(Sha256dHash, u32, u64)>, [u8; 32]), Error> {
+        let mut outputs = vec![];
+        for i in 0..self.entries.len() {
+            let entry = self.lookup(dongle, i)?;
+            if entry.state == EntryState::Received && !entry.spent {
+                outputs.push((Sha256dHash::from(&entry.txid[..]), entry.vout, entry.amount));
+            }
+        }
+        let commitment = commit_to_outputs(&mut outputs);
+        Ok((outputs, commitment))
+    }
+
+    /// Signs a message with the wallet's attestation key, which is never used
+    /// to receive coins. Returns the key's address along with the signature.
+    pub fn sign_attestation<D: Dongle>(&self, dongle: &mut D, msg: &str) -> Result<(String, [u8; 64]), Error> {
+        let path = bip32_path(self.network, self.account, KeyPurpose::Attestation, 0);
+        let key = dongle.get_public_key(&path, false)?;
+        eprintln!("The dongle will ask you to sign hash {}", hash_sha256(msg.as_bytes()).to_hex());
+        let sig = dongle.sign_message(msg.as_bytes(), &path)?;
+        Ok((key.b58_address, sig))
+    }
+
     /// Scan the wallet finding funds in excess of `total_amount` as well
     /// as the next available unused address for change
     pub fn get_inputs_and_change<D: Dongle>(&self, dongle: &mut D, fee_rate: u64, spend: &mut spend::Spend) -> Result<(), Error> {
+        self.select_inputs_and_change(dongle, fee_rate, &CoinSelection::InOrder, spend)
+    }
+
+    /// Scan the wallet choosing inputs according to `selection`, as well as
+    /// the next available unused address for change
+    pub fn select_inputs_and_change<D: Dongle>(&self, dongle: &mut D, fee_rate: u64, selection: &CoinSelection, spend: &mut spend::Spend) -> Result<(), Error> {
         let mut found_amount = 0;
         let mut found_change = false;
 
         // (Over)estimate tx size for fee accounting purposes
-        let mut size_bytes = (13 + ((spend.output.len() + 1) * 34)) as u64;
+        let mut size_bytes = base_size_estimate(&spend.output);
         let mut total_amount = 0;
         for output in &spend.output {
             total_amount += output.value;
@@ -409,26 +1206,56 @@ impl EncryptedWallet {
                 EntryState::Valid => { }
                 EntryState::Received => {
                     if !entry.spent {
-                        if found_amount < total_amount + (size_bytes * fee_rate / 1000) {
+                        let take = match *selection {
+                            CoinSelection::InOrder => found_amount < total_amount + (size_bytes * fee_rate / 1000),
+                            CoinSelection::Consolidate { below, max_inputs, ref exclude } => {
+                                // Outputs worth less than the fee to spend them are not worth taking
+                                entry.amount < below && entry.amount > INPUT_SIZE_ESTIMATE * fee_rate / 1000 &&
+                                    spend.input.len() < max_inputs && !exclude.contains(&i)
+                            }
+                            CoinSelection::Explicit(ref outpoints) => {
+                                let txid = Sha256dHash::from(&entry.txid[..]);
+                                outpoints.iter().any(|&(ref t, v)| *t == txid && v == entry.vout)
+                            }
+                        };
+                        if take {
                             spend.input.push(spend::Input::from_entry(&entry));
-                            size_bytes += 150; // 40 txin stuff, 72 sig, 33 key
+                            size_bytes += INPUT_SIZE_ESTIMATE;
                             found_amount += entry.amount;
                         }
                     }
                 }
             }
-            // Early quit if we have change and sufficient funds
-            if found_change && found_amount >= total_amount + (size_bytes * fee_rate / 1000) {
+            // Early quit if we have change and enough inputs
+            let done = match *selection {
+                CoinSelection::InOrder => found_amount >= total_amount + (size_bytes * fee_rate / 1000),
+                CoinSelection::Consolidate { max_inputs, .. } => spend.input.len() >= max_inputs,
+                CoinSelection::Explicit(ref outpoints) => spend.input.len() == outpoints.len()
+            };
+            if found_change && done {
                 break;
             }
         }
 
         // Assess what we found and return errors if necessary
+        if let CoinSelection::Explicit(ref outpoints) = *selection {
+            for &(ref txid, vout) in outpoints {
+                if !spend.input.iter().any(|input| input.txin.previous_output.txid == *txid && input.txin.previous_output.vout == vout) {
+                    return Err(Error::InputNotFound(*txid, vout));
+                }
+            }
+        }
         let total_needed = total_amount + (size_bytes * fee_rate / 1000);
         if found_amount < total_needed {
             return Err(Error::InsufficientFunds(found_amount, total_needed));
         }
         let computed_change = found_amount - total_needed;
+        if let CoinSelection::Consolidate { .. } = *selection {
+            // A consolidation pays only to change, so there must be some
+            if computed_change < CHANGE_DUST {
+                return Err(Error::InsufficientFunds(found_amount, total_needed + CHANGE_DUST));
+            }
+        }
         if computed_change < CHANGE_DUST {
             spend.change_amount = 0;
             spend.change_path = [0; 5];
@@ -454,19 +1281,123 @@ impl EncryptedWallet {
         dongle.transaction_input_finalize(spend)?;
         let signing_pk_path = bip32_path(self.network, self.account, KeyPurpose::Address, index as u32);
         let signing_pk = dongle.get_public_key(&signing_pk_path, false)?;
-        let mut vec_sig = dongle.transaction_sign(signing_pk_path, SigHashType::All, 0)?;
+        let mut vec_sig = dongle.transaction_sign(signing_pk_path, SigHashType::All, spend.lock_time)?;
         vec_sig[0] = 0x30;
         Ok(script::Builder::new().push_slice(&vec_sig[..])
                                  .push_slice(&signing_pk.public_key.serialize())
                                  .into_script())
     }
 
+    /// Checks every entry of the wallet, returning a list of those with problems.
+    /// Besides checking each entry's signature, this recomputes each address
+    /// from the public key the dongle gives for its path, rather than trusting
+    /// the address string the dongle reports, and checks that no two entries
+    /// record the same output and that only funded entries are marked spent.
+    pub fn reverify<D: Dongle>(&self, dongle: &mut D) -> Result<Vec<(usize, EntryProblem)>, Error> {
+        let mut problems = vec![];
+        let mut outputs = HashMap::new();
+        for i in 0..self.entries.len() {
+            info!("Reverifying entry {}", i);
+            let entry = match self.lookup(dongle, i) {
+                Ok(entry) => entry,
+                Err(Error::EntryCorrupted(_)) => {
+                    problems.push((i, EntryProblem::Corrupted));
+                    continue;
+                }
+                Err(e) => return Err(e)
+            };
+            if entry.state == EntryState::Invalid {
+                problems.push((i, EntryProblem::BadSignature));
+            }
+            if entry.state == EntryState::Received {
+                if let Some(&first) = outputs.get(&(entry.txid, entry.vout)) {
+                    problems.push((i, EntryProblem::DuplicateOutput(first)));
+                } else {
+                    outputs.insert((entry.txid, entry.vout), i);
+                }
+            } else if entry.spent {
+                problems.push((i, EntryProblem::SpentUnfunded));
+            }
+
+            let key = dongle.get_public_key(&entry.bip32_path, false)?;
+            let spk = entry.address.script_pubkey();
+            if spk != p2pkh_script(&hash160(&key.public_key.serialize()[..])) &&
+               spk != p2pkh_script(&hash160(&key.public_key.serialize_uncompressed()[..])) {
+                problems.push((i, EntryProblem::AddressMismatch));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Computes a SHA256 digest of the decrypted contents of the wallet, so
+    /// that two wallet files can be compared for equality even though every
+    /// rerandomization changes their encrypted bytes
+    pub fn logical_hash<D: Dongle>(&self, dongle: &mut D) -> Result<[u8; 32], Error> {
+        let mut hasher = sha2::Sha256::new();
+        let mut header = [0; 12];
+        BigEndian::write_u64(&mut header[0..8], self.magic());
+        BigEndian::write_u32(&mut header[8..12], self.account);
+        hasher.input(&header);
+        for i in 0..self.entries.len() {
+            let mut tmp = [0; DECRYPTED_ENTRY_SIZE];
+            decrypt(dongle, self.network, self.account, i, self.layer(), &self.entries[i], &mut tmp)?;
+            hasher.input(&tmp);
+        }
+        let mut result = [0; 32];
+        hasher.result(&mut result);
+        Ok(result)
+    }
+
+    /// The magic bytes identifying this wallet's network. This is the magic
+    /// of the original unversioned file format, which is also used by backups
+    /// and by `logical_hash`, so that neither depends on which version wrote
+    /// the wallet.
+    fn magic(&self) -> u64 {
+        if self.network == Network::Testnet { MAGIC_TESTNET } else { MAGIC }
+    }
+
+    /// The passphrase key layered into entry keys, if any
+    fn layer(&self) -> Option<&[u8; 32]> {
+        self.passphrase.as_ref().map(|&(_, ref key)| key)
+    }
+
     /// Accessor for the account number
     pub fn account(&self) -> u32 { self.account }
+    /// Whether entry keys are layered with a passphrase
+    pub fn has_passphrase(&self) -> bool { self.passphrase.is_some() }
+    /// Whether the wallet file had a checksum (which was verified on loading)
+    pub fn has_checksum(&self) -> bool { self.checksummed }
     /// Accessor for the number of entries
     pub fn n_entries(&self) -> usize { self.entries.len() }
 }
 
+/// A problem found with an entry by `EncryptedWallet::reverify`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EntryProblem {
+    /// The entry did not decrypt to valid data
+    Corrupted,
+    /// The entry's signature did not verify under its key
+    BadSignature,
+    /// The entry's address does not pay to its key
+    AddressMismatch,
+    /// The entry records the same output as the entry with the given index
+    DuplicateOutput(usize),
+    /// The entry is marked spent but never received anything
+    SpentUnfunded
+}
+
+impl fmt::Display for EntryProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EntryProblem::Corrupted => f.write_str("entry did not decrypt to valid data"),
+            EntryProblem::BadSignature => f.write_str("entry signature does not verify"),
+            EntryProblem::AddressMismatch => f.write_str("address does not match the key for this index"),
+            EntryProblem::DuplicateOutput(first) => write!(f, "records the same output as entry {}", first),
+            EntryProblem::SpentUnfunded => f.write_str("marked spent but never received funds")
+        }
+    }
+}
+
 /// Whether an entry has been used
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum EntryState {
@@ -520,7 +1451,8 @@ pub struct Entry {
     pub vout: u32,
     /// The amount of the first receive to this address (or zero if it's yet unused)
     pub amount: u64,
-    /// The date the entry was updated, in ASCII `YYYY-MM-DD HH:MM:SS+ZZZZ`
+    /// The date the entry was updated, in ASCII `YYYY-MM-DD HH:MM:SS+ZZZZ`. This
+    /// is UTC for entries written by this version, local time for older ones.
     pub date: [u8; 24],
     /// A recent bitcoin blockhash
     pub blockhash: [u8; 32],
@@ -530,9 +1462,19 @@ pub struct Entry {
     pub note: String
 }
 
+/// Column names for `Entry::to_csv`. The wallet does not record when an
+/// output is spent, only that it has been, so each row is a receive.
+pub const ENTRY_CSV_HEADER: &'static str = "date,index,path,address,txid,vout,amount,spent,user,note,total_received";
+
 impl Entry {
+    /// The time the entry was updated, in seconds since the epoch, or None if it
+    /// has never been updated. Use this rather than `date` to order entries.
+    pub fn timestamp(&self) -> Option<i64> {
+        parse_timestamp(&self.date)
+    }
+
     /// Encode an entry, sign the second half of it, and embed the signature in the entry
-    fn sign_and_encrypt<D: Dongle>(&self, dongle: &mut D, network: Network, account: u32, index: usize) -> Result<[u8; ENCRYPTED_ENTRY_SIZE], Error> {
+    fn sign_and_encrypt<D: Dongle>(&self, dongle: &mut D, network: Network, account: u32, index: usize, layer: Option<&[u8; 32]>) -> Result<[u8; ENCRYPTED_ENTRY_SIZE], Error> {
         let mut input = [0; DECRYPTED_ENTRY_SIZE];
         // Copy out the signed data
         input[64..120].copy_from_slice(&self.trusted_input);
@@ -548,22 +1490,26 @@ impl Entry {
         let sig = {
             let to_sign = &input[64..336];
 
-            println!("The dongle will ask you to sign hash {}", hash_sha256(to_sign).to_hex());
-            println!("This is the SHA256 of data {}", to_sign.to_hex());
+            // Prompts go to stderr so they do not mix with JSON output on stdout
+            eprintln!("The dongle will ask you to sign hash {}", hash_sha256(to_sign).to_hex());
+            eprintln!("This is the SHA256 of data {}", to_sign.to_hex());
             dongle.sign_message(to_sign, &bip32_path(network, account, KeyPurpose::Address, index as u32))?
         };
         input[0..64].copy_from_slice(&sig);
 
         // AES-encrypt the whole thing
         let mut ret = [0; ENCRYPTED_ENTRY_SIZE];
-        encrypt(dongle, network, account, index, &input, &mut ret)?;
+        encrypt(dongle, network, account, index, layer, &input, &mut ret)?;
         Ok(ret)
     }
 
     /// Interpret a byte sequence as an entry; verify its signature if it's not blank
-    fn decrypt_and_verify<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, input: &[u8; ENCRYPTED_ENTRY_SIZE]) -> Result<Entry, Error> {
+    fn decrypt_and_verify<D: Dongle>(dongle: &mut D, network: Network, account: u32, index: usize, layer: Option<&[u8; 32]>, input: &[u8; ENCRYPTED_ENTRY_SIZE]) -> Result<Entry, Error> {
         let mut data = [0u8; DECRYPTED_ENTRY_SIZE];
-        decrypt(dongle, network, account, index, &input[..], &mut data)?;
+        decrypt(dongle, network, account, index, layer, &input[..], &mut data)?;
+        if !is_plausible_entry(&data) {
+            return Err(Error::EntryCorrupted(index));
+        }
 
         let path = bip32_path(network, account, KeyPurpose::Address, index as u32);
         let key = dongle.get_public_key(&path, false)?;
@@ -628,10 +1574,72 @@ impl Entry {
         }
     }
 
+    /// Encode the entry as a JSON object. As with the `Display` output, an
+    /// unused entry does not reveal its address.
+    pub fn to_json(&self) -> String {
+        let state = match self.state {
+            EntryState::Unused => return format!("{{\"index\":{},\"state\":\"unused\"}}", self.index),
+            EntryState::Invalid => "invalid",
+            EntryState::Valid => "valid",
+            EntryState::Received => "received"
+        };
+        let mut ret = format!("{{\"index\":{},\"state\":\"{}\",\"path\":\"{}\",\"address\":{}",
+                              self.index, state, bip32_path_string(&self.bip32_path), json_string(&self.address.to_string()));
+        if self.state == EntryState::Received {
+            let txid = Sha256dHash::from(&self.txid[..]);
+            ret.push_str(&format!(",\"txid\":\"{}\",\"vout\":{},\"amount\":{},\"spent\":{}", txid, self.vout, self.amount, self.spent));
+        }
+        if let Some(timestamp) = self.timestamp() {
+            ret.push_str(&format!(",\"timestamp\":{}", timestamp));
+        }
+        let blockhash = Sha256dHash::from(&self.blockhash[..]);
+        ret.push_str(&format!(",\"created\":{},\"after\":\"{}\",\"user\":{},\"note\":{}}}",
+                              json_string(&String::from_utf8_lossy(&self.date[..])),
+                              blockhash,
+                              json_string(self.user.trim_right_matches('\0')),
+                              json_string(self.note.trim_right_matches('\0'))));
+        ret
+    }
+
+    /// Encode a received entry as a CSV row with the columns of `ENTRY_CSV_HEADER`,
+    /// given the total received by this and all earlier rows
+    pub fn to_csv(&self, total_received: u64) -> String {
+        let txid = Sha256dHash::from(&self.txid[..]);
+        format!("{},{},{},{},{},{},{},{},{},{},{}",
+                csv_field(&String::from_utf8_lossy(&self.date[..])),
+                self.index,
+                bip32_path_string(&self.bip32_path),
+                self.address,
+                txid,
+                self.vout,
+                self.amount,
+                self.spent,
+                csv_field(self.user.trim_right_matches('\0')),
+                csv_field(self.note.trim_right_matches('\0')),
+                total_received)
+    }
+
+    /// Encode the entry's address as a `pkh()` output descriptor with key origin
+    /// and checksum, given the public key at its path and the dongle's master
+    /// fingerprint. Returns None if the address pays to neither form of the key.
+    pub fn to_descriptor(&self, key: &secp256k1::key::PublicKey, fingerprint: &[u8; 4]) -> Option<String> {
+        let spk = self.address.script_pubkey();
+        let key_hex = if spk == p2pkh_script(&hash160(&key.serialize()[..])) {
+            key.serialize()[..].to_hex()
+        } else if spk == p2pkh_script(&hash160(&key.serialize_uncompressed()[..])) {
+            key.serialize_uncompressed()[..].to_hex()
+        } else {
+            return None;
+        };
+        // Drop the leading `m` of the path to get the key origin
+        let desc = format!("pkh([{}{}]{})", fingerprint.to_hex(), &bip32_path_string(&self.bip32_path)[1..], key_hex);
+        descriptor_checksum(&desc).map(|checksum| format!("{}#{}", desc, checksum))
+    }
+
     /// Produce a Bitcoin signed message using this entry's address
     pub fn sign_message<D: Dongle>(&self, dongle: &mut D, msg: &str) -> Result<[u8; 64], Error> {
         let msg = msg.as_bytes();
-        println!("The dongle will ask you to sign hash {}", hash_sha256(msg).to_hex());
+        eprintln!("The dongle will ask you to sign hash {}", hash_sha256(msg).to_hex());
         Ok(dongle.sign_message(msg, &self.bip32_path)?)
     }
 }
@@ -649,6 +1657,7 @@ impl fmt::Display for Entry {
             EntryState::Received => writeln!(f, "Signed Entry (used):")?
         }
         writeln!(f, "   index: {}", self.index)?;
+        writeln!(f, "    path: {}", bip32_path_string(&self.bip32_path))?;
         writeln!(f, " address: {}", self.address)?;
         if self.state != EntryState::Received {
             writeln!(f, "    txid: no associated output")?;
@@ -668,3 +1677,214 @@ impl fmt::Display for Entry {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use bitcoin::{Address, TxOut};
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script;
+    use bitcoin::network::constants::Network;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::str::FromStr;
+    use constants::wallet::{BACKUP_MAGIC, DECRYPTED_ENTRY_SIZE, ENCRYPTED_ENTRY_SIZE, MAGIC, MAGIC_FLAGS, MAGIC_TESTNET, MAX_DATA_BYTES};
+    use constants::wallet::header_flags;
+    use error::Error;
+    use util::hash_sha256;
+    use super::{base_size_estimate, bip32_path, bip32_path_string, parse_timestamp, read_backup_header, read_entries, Header, KeyPurpose};
+
+    /// A header with every optional field, as written by `save`
+    fn full_header() -> Vec<u8> {
+        let mut data = vec![];
+        data.write_u64::<BigEndian>(MAGIC_FLAGS).unwrap();
+        data.write_u32::<BigEndian>(7).unwrap();
+        data.write_u16::<BigEndian>(1).unwrap();
+        data.write_u8(2).unwrap();
+        data.write_u8(3).unwrap();
+        data.write_u32::<BigEndian>(header_flags::FINGERPRINT | header_flags::CHECKSUM | header_flags::PASSPHRASE).unwrap();
+        data.extend_from_slice(&[0xaa; 4]);
+        data.extend_from_slice(&[0xbb; 16]);
+        data
+    }
+
+    #[test]
+    fn bip32_path_high_index() {
+        let path = bip32_path(Network::Bitcoin, 0x7fffffff, KeyPurpose::Address, 0x7fffffff);
+        assert_eq!(path, [0x8000002c, 0x80000000, 0xffffffff, 0x80000002, 0xffffffff]);
+        assert_eq!(bip32_path_string(&path), "m/44'/0'/2147483647'/2'/2147483647'");
+        assert_eq!(bip32_path_string(&[]), "m");
+    }
+
+    #[test]
+    fn timestamp_offsets() {
+        let utc = parse_timestamp(b"2017-06-01 12:00:00+0000").unwrap();
+        assert_eq!(utc, 1496318400);
+        assert_eq!(parse_timestamp(b"2017-06-01 08:00:00-0400"), Some(utc));
+        assert_eq!(parse_timestamp(b"2017-06-01 17:30:00+0530"), Some(utc));
+        assert_eq!(parse_timestamp(&[0; 24]), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bip32_path_index_overflow() {
+        bip32_path(Network::Bitcoin, 0, KeyPurpose::Address, 0x80000000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bip32_path_account_overflow() {
+        bip32_path(Network::Testnet, 0x80000000, KeyPurpose::AesKey, 0);
+    }
+
+    #[test]
+    fn header_fields() {
+        let data = full_header();
+        let header = Header::read(&mut &data[..]).unwrap();
+        assert_eq!(header.network, Network::Bitcoin);
+        assert_eq!(header.account, 7);
+        assert_eq!(header.writer_version, Some((1, 2, 3)));
+        assert_eq!(header.fingerprint, Some([0xaa; 4]));
+        assert_eq!(header.salt, Some([0xbb; 16]));
+        assert!(header.checksummed);
+        assert_eq!(header.size, data.len());
+        assert_eq!(header.size, 40);
+
+        // The original header has only a magic and an account
+        let mut data = vec![];
+        data.write_u64::<BigEndian>(MAGIC).unwrap();
+        data.write_u32::<BigEndian>(5).unwrap();
+        let header = Header::read(&mut &data[..]).unwrap();
+        assert_eq!(header.network, Network::Bitcoin);
+        assert_eq!(header.account, 5);
+        assert_eq!(header.writer_version, None);
+        assert_eq!(header.fingerprint, None);
+        assert_eq!(header.salt, None);
+        assert!(!header.checksummed);
+        assert_eq!(header.size, 12);
+
+        let mut data = vec![];
+        data.write_u64::<BigEndian>(MAGIC_TESTNET).unwrap();
+        data.write_u32::<BigEndian>(0).unwrap();
+        let header = Header::read(&mut &data[..]).unwrap();
+        assert_eq!(header.network, Network::Testnet);
+        assert_eq!(header.size, 12);
+    }
+
+    #[test]
+    fn header_truncated() {
+        let data = full_header();
+        for len in 0..data.len() {
+            match Header::read(&mut &data[..len]) {
+                Err(Error::Io(_)) => {}
+                other => panic!("header truncated to {} bytes parsed as {:?}", len, other)
+            }
+        }
+    }
+
+    #[test]
+    fn header_garbage() {
+        let mut data = full_header();
+        data[0] ^= 0xff;
+        match Header::read(&mut &data[..]) {
+            Err(Error::WalletWrongMagic(_)) => {}
+            other => panic!("garbage magic parsed as {:?}", other)
+        }
+        match Header::read(&mut &[0xff; 64][..]) {
+            Err(Error::WalletWrongMagic(_)) => {}
+            other => panic!("garbage parsed as {:?}", other)
+        }
+
+        let mut data = full_header();
+        data[8] = 0x80;
+        match Header::read(&mut &data[..]) {
+            Err(Error::AccountOutOfRange(0x80000007)) => {}
+            other => panic!("hardened account parsed as {:?}", other)
+        }
+
+        let mut data = full_header();
+        data[19] |= 0x80;
+        match Header::read(&mut &data[..]) {
+            Err(Error::WalletUnknownFlags(0x8e)) => {}
+            other => panic!("unknown flags parsed as {:?}", other)
+        }
+    }
+
+    #[test]
+    fn entries_size_and_checksum() {
+        let mut data = full_header();
+        let header_size = data.len();
+        data.extend_from_slice(&[0x11; ENCRYPTED_ENTRY_SIZE]);
+        data.extend_from_slice(&[0x22; ENCRYPTED_ENTRY_SIZE]);
+        let checksum = hash_sha256(&data);
+        data.extend_from_slice(&checksum);
+
+        let entries = read_entries(&data, header_size, true).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(&entries[1][..], &[0x22; ENCRYPTED_ENTRY_SIZE][..]);
+        // No entries at all is fine
+        let empty = full_header();
+        let mut empty_data = empty.clone();
+        empty_data.extend_from_slice(&hash_sha256(&empty));
+        assert_eq!(read_entries(&empty_data, header_size, true).unwrap().len(), 0);
+
+        for len in 0..data.len() {
+            match read_entries(&data[..len], header_size, true) {
+                Err(Error::WalletWrongSize(n)) if n == len => {}
+                // Cut at an entry boundary, so only the checksum catches it
+                Err(Error::WalletBadChecksum) => {}
+                other => panic!("file truncated to {} bytes read as {:?}", len, other.map(|e| e.len()))
+            }
+        }
+
+        let mut corrupted = data.clone();
+        corrupted[header_size + 5] ^= 1;
+        match read_entries(&corrupted, header_size, true) {
+            Err(Error::WalletBadChecksum) => {}
+            other => panic!("corrupted file read as {:?}", other.map(|e| e.len()))
+        }
+        // Without a checksum the same file is the wrong size
+        match read_entries(&data, header_size, false) {
+            Err(Error::WalletWrongSize(_)) => {}
+            other => panic!("checksummed file read unchecksummed as {:?}", other.map(|e| e.len()))
+        }
+    }
+
+    #[test]
+    fn backup_header_account() {
+        let mut data = vec![];
+        data.write_u64::<BigEndian>(BACKUP_MAGIC).unwrap();
+        data.write_u64::<BigEndian>(MAGIC_TESTNET).unwrap();
+        data.write_u32::<BigEndian>(3).unwrap();
+        data.write_u32::<BigEndian>(2).unwrap();
+        data.extend_from_slice(&[0; 32]);
+        data.extend_from_slice(&[0; 2 * DECRYPTED_ENTRY_SIZE]);
+        data.extend_from_slice(&[0; 32]);
+        assert_eq!(read_backup_header(&data).unwrap(), (Network::Testnet, 3, 2));
+
+        data[16] = 0x80;
+        match read_backup_header(&data) {
+            Err(Error::AccountOutOfRange(0x80000003)) => {}
+            other => panic!("hardened account in backup parsed as {:?}", other)
+        }
+    }
+
+    #[test]
+    fn size_estimate_op_return() {
+        let addr = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2").unwrap();
+        let payment = TxOut { value: 100000, script_pubkey: addr.script_pubkey() };
+        assert_eq!(base_size_estimate(&[payment.clone()]), 13 + 34 + 34);
+
+        // A maximal OP_RETURN is OP_RETURN, OP_PUSHDATA1, a length byte and
+        // the data, and its fee must cover all of it
+        let data = TxOut {
+            value: 0,
+            script_pubkey: script::Builder::new().push_opcode(opcodes::All::OP_RETURN)
+                                                 .push_slice(&[0xab; MAX_DATA_BYTES])
+                                                 .into_script()
+        };
+        assert_eq!(data.script_pubkey.len(), MAX_DATA_BYTES + 3);
+        let size = base_size_estimate(&[payment, data]);
+        assert_eq!(size, 13 + 34 + (MAX_DATA_BYTES as u64 + 3 + 9) + 34);
+        // The fee charged at 10 satoshi per byte covers the whole OP_RETURN
+        let fee_rate = 10000;
+        assert_eq!(size * fee_rate / 1000, 1730);
+    }
+}